@@ -0,0 +1,540 @@
+use std::{env, fs, path::Path, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::error::{LeoError, Result};
+use crate::image_format::ImageFormat;
+use crate::storage::StorageBackend;
+
+// Default location of the configuration file
+pub const DEFAULT_CONFIG_FILE: &str = "leo.toml";
+
+// Settings a command needs, only those are validated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigScope {
+    // State maintenance commands, which only open the storage
+    Storage,
+    // Running the bot or a backfill, which needs every credential
+    Bot,
+}
+
+// Complete bot configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Tweet processing settings
+    pub bot: BotConfig,
+    // Twitter account settings
+    pub twitter: TwitterConfig,
+    // OpenAI settings for prompt rewriting and image generation
+    pub openai: OpenAiConfig,
+    // Google Vision settings
+    pub vision: VisionConfig,
+    // Retry and timeout settings per upstream
+    pub http: HttpConfig,
+    // Size limits of images sent to each destination
+    pub media: MediaConfig,
+}
+
+// Settings for the tweet processing loop
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+    // Prompt used to turn labels into an image description, `{}` is replaced by the labels
+    pub translate_prompt: String,
+    // Text of the reply tweet, `{}` is replaced by the requester username
+    pub reply_text: String,
+    // Maximum number of mentions fetched per search page
+    pub max_tweets: i32,
+    // Maximum number of search pages fetched per iteration while catching up
+    pub max_pages: u32,
+    // Seconds to sleep between iterations while no mentions arrive
+    pub poll_interval_secs: u64,
+    // Seconds to sleep between iterations while mentions keep arriving
+    pub min_poll_interval_secs: u64,
+    // Upper bound in seconds of the delay after failed or rate limited iterations
+    pub max_poll_backoff_secs: u64,
+    // Number of tweets processed in parallel
+    pub workers: usize,
+    // Failed attempts after which a tweet is moved to the dead letters
+    pub max_attempts: u32,
+    // Backend used to persist processed tweets
+    pub storage_backend: StorageBackend,
+    // Path of the processed tweets state file or database
+    pub storage_file: String,
+    // Move a corrupt JSON state file aside and start empty instead of refusing to start
+    pub recover_state: bool,
+    // Days a finished record is kept after its last change, 0 keeps records forever
+    pub retention_days: u64,
+    // Mentions older than this many hours are skipped instead of answered, 0 answers every mention
+    pub max_mention_age_hours: u64,
+    // Seconds to wait between search pages while backfilling
+    pub backfill_page_delay_secs: u64,
+    // Seconds in-flight tweets may take to finish after a shutdown signal
+    pub shutdown_grace_secs: u64,
+}
+
+// Credentials of the Twitter account the bot runs as
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwitterConfig {
+    // Username for the Twitter account
+    pub username: String,
+    // Password for the Twitter account
+    pub password: String,
+    // Email associated with the Twitter account
+    pub email: String,
+    // Two-factor authentication secret, empty when 2FA is disabled
+    pub two_factor_secret: String,
+    // File the session cookies are saved to and restored from, empty to log in on every start
+    pub cookie_file: String,
+    // Maximum number of concurrent scraper calls
+    pub max_concurrent: usize,
+}
+
+// Settings for the OpenAI APIs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAiConfig {
+    // OpenAI API key
+    pub api_key: String,
+    // Completion model used to rewrite descriptions
+    pub completion_model: String,
+    // Image model used to draw the artwork
+    pub image_model: String,
+    // Quality requested from the image model
+    pub image_quality: String,
+    // Width of the generated image
+    pub image_width: u32,
+    // Height of the generated image
+    pub image_height: u32,
+    // Maximum number of concurrent completion requests
+    pub max_concurrent_completions: usize,
+    // Maximum number of concurrent image generation requests
+    pub max_concurrent_images: usize,
+}
+
+// Settings for the Google Vision API
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VisionConfig {
+    // Path of the Google service account key file
+    pub service_account_file: String,
+    // Maximum number of labels requested per image
+    pub max_results: u8,
+    // Also describe the profile banner and add its labels to those of the avatar
+    pub describe_banner: bool,
+    // Maximum number of concurrent Vision requests
+    pub max_concurrent: usize,
+}
+
+// Size limits of images per destination
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaConfig {
+    // Artwork uploaded to Twitter
+    pub upload: MediaTarget,
+    // Avatars sent to the describer
+    pub describe: MediaTarget,
+    // Limits of avatar and banner downloads
+    pub download: DownloadConfig,
+}
+
+// Limits an image must fit before it is sent to a destination
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaTarget {
+    // Largest accepted encoded size in bytes
+    pub max_bytes: usize,
+    // Largest accepted width and height in pixels
+    pub max_dimension: u32,
+    // Format images are re-encoded to when they do not fit as they are
+    pub format: ImageFormat,
+    // Lowest JPEG quality tried before the image is downscaled further
+    pub min_quality: u8,
+}
+
+// Limits of image downloads from user controlled URLs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadConfig {
+    // Hosts images may be downloaded from, subdomains included, over HTTPS only
+    pub allowed_hosts: Vec<String>,
+    // Largest accepted response body in bytes
+    pub max_bytes: usize,
+    // Maximum number of redirects followed, each target must be an allowed host too
+    pub max_redirects: usize,
+}
+
+// Retry and timeout settings for every upstream API
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    // OpenAI image generation
+    pub openai: UpstreamConfig,
+    // OpenAI prompt completions, sent by the rig client which only honors the request timeout
+    pub openai_completion: UpstreamConfig,
+    // Google OAuth token exchange
+    pub google_token: UpstreamConfig,
+    // Google Vision annotation
+    pub vision: UpstreamConfig,
+    // Avatar downloads
+    pub avatar: UpstreamConfig,
+}
+
+// Retry and timeout settings for a single upstream API
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    // Maximum number of attempts per request, including the first one
+    pub max_attempts: u32,
+    // Delay before the first retry in milliseconds, doubled on every attempt
+    pub initial_backoff_ms: u64,
+    // Upper bound of the retry delay in milliseconds, longer Retry-After values give up
+    pub max_backoff_ms: u64,
+    // Seconds allowed to establish a connection
+    pub connect_timeout_secs: u64,
+    // Seconds allowed for the whole request including the response body
+    pub request_timeout_secs: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            openai: UpstreamConfig {
+                request_timeout_secs: 180,
+                ..UpstreamConfig::default()
+            },
+            openai_completion: UpstreamConfig::default(),
+            google_token: UpstreamConfig::default(),
+            vision: UpstreamConfig::default(),
+            avatar: UpstreamConfig::default(),
+        }
+    }
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            upload: MediaTarget::default(),
+            // Label detection needs no more than a small preview
+            describe: MediaTarget {
+                max_bytes: 1024 * 1024,
+                max_dimension: 1024,
+                ..MediaTarget::default()
+            },
+            download: DownloadConfig::default(),
+        }
+    }
+}
+
+impl Default for DownloadConfig {
+    // Twitter serves avatars and banners from its media hosts
+    fn default() -> Self {
+        Self {
+            allowed_hosts: vec!["pbs.twimg.com".into(), "abs.twimg.com".into()],
+            max_bytes: 10 * 1024 * 1024,
+            max_redirects: 3,
+        }
+    }
+}
+
+impl Default for MediaTarget {
+    // Twitter accepts images up to 5 MB
+    fn default() -> Self {
+        Self {
+            max_bytes: 5 * 1024 * 1024,
+            max_dimension: 4096,
+            format: ImageFormat::Jpeg,
+            min_quality: 60,
+        }
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            connect_timeout_secs: 10,
+            request_timeout_secs: 60,
+        }
+    }
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self {
+            translate_prompt: String::new(),
+            reply_text: String::new(),
+            max_tweets: 20,
+            max_pages: 10,
+            poll_interval_secs: 60,
+            min_poll_interval_secs: 15,
+            max_poll_backoff_secs: 900,
+            workers: 4,
+            max_attempts: 3,
+            storage_backend: StorageBackend::Json,
+            storage_file: "tweets.json".into(),
+            recover_state: false,
+            retention_days: 30,
+            max_mention_age_hours: 24,
+            backfill_page_delay_secs: 5,
+            shutdown_grace_secs: 120,
+        }
+    }
+}
+
+impl Default for TwitterConfig {
+    fn default() -> Self {
+        Self {
+            username: String::new(),
+            password: String::new(),
+            email: String::new(),
+            two_factor_secret: String::new(),
+            cookie_file: "twitter_cookies.txt".into(),
+            max_concurrent: 2,
+        }
+    }
+}
+
+impl Default for OpenAiConfig {
+    fn default() -> Self {
+        Self {
+            api_key: String::new(),
+            completion_model: "gpt-4".into(),
+            image_model: "dall-e-3".into(),
+            image_quality: "hd".into(),
+            image_width: 1792,
+            image_height: 1024,
+            max_concurrent_completions: 4,
+            max_concurrent_images: 2,
+        }
+    }
+}
+
+impl Default for VisionConfig {
+    fn default() -> Self {
+        Self {
+            service_account_file: "service_account.json".into(),
+            max_results: 10,
+            describe_banner: false,
+            max_concurrent: 4,
+        }
+    }
+}
+
+impl Config {
+    // Load configuration from the file named by LEO_CONFIG (or leo.toml), apply
+    // environment overrides and validate what the scope needs
+    pub fn load(scope: ConfigScope) -> Result<Self> {
+        let config = match env::var("LEO_CONFIG") {
+            Ok(path) => Self::read(Path::new(&path))?,
+            // Only the implicit file is optional, a .env file alone is enough
+            Err(_) if !Path::new(DEFAULT_CONFIG_FILE).exists() => Config::default(),
+            Err(_) => Self::read(Path::new(DEFAULT_CONFIG_FILE))?,
+        };
+        config.finish(scope)
+    }
+
+    // Load configuration from a TOML file that must exist
+    pub fn load_from(path: impl AsRef<Path>, scope: ConfigScope) -> Result<Self> {
+        Self::read(path.as_ref())?.finish(scope)
+    }
+
+    // Parse a TOML file
+    fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| LeoError::Config(format!("Unable to read {} {}", path.display(), err)))?;
+        toml::from_str(&contents).map_err(|err| LeoError::Config(format!("Invalid {} {}", path.display(), err)))
+    }
+
+    // Apply environment overrides and validate the result
+    fn finish(mut self, scope: ConfigScope) -> Result<Self> {
+        let mut problems = Vec::new();
+        self.apply_env(&mut problems);
+        self.validate(scope, &mut problems);
+
+        if problems.is_empty() {
+            Ok(self)
+        } else {
+            Err(LeoError::Config(problems.join("; ")))
+        }
+    }
+
+    // Override file values with environment variables when they are set
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        override_string("TRANSLATE_PROMPT", &mut self.bot.translate_prompt);
+        override_string("TWITTER_REPLY_TEXT", &mut self.bot.reply_text);
+        override_parsed("LEO_MAX_TWEETS", &mut self.bot.max_tweets, problems);
+        override_parsed("LEO_MAX_PAGES", &mut self.bot.max_pages, problems);
+        override_parsed("LEO_POLL_INTERVAL_SECS", &mut self.bot.poll_interval_secs, problems);
+        override_parsed("LEO_MIN_POLL_INTERVAL_SECS", &mut self.bot.min_poll_interval_secs, problems);
+        override_parsed("LEO_MAX_POLL_BACKOFF_SECS", &mut self.bot.max_poll_backoff_secs, problems);
+        override_parsed("LEO_WORKERS", &mut self.bot.workers, problems);
+        override_parsed("LEO_MAX_ATTEMPTS", &mut self.bot.max_attempts, problems);
+        override_parsed("LEO_STORAGE_BACKEND", &mut self.bot.storage_backend, problems);
+        override_string("LEO_STORAGE_FILE", &mut self.bot.storage_file);
+        override_parsed("LEO_RECOVER_STATE", &mut self.bot.recover_state, problems);
+        override_parsed("LEO_RETENTION_DAYS", &mut self.bot.retention_days, problems);
+        override_parsed("LEO_MAX_MENTION_AGE_HOURS", &mut self.bot.max_mention_age_hours, problems);
+        override_parsed("LEO_BACKFILL_PAGE_DELAY_SECS", &mut self.bot.backfill_page_delay_secs, problems);
+        override_parsed("LEO_SHUTDOWN_GRACE_SECS", &mut self.bot.shutdown_grace_secs, problems);
+
+        override_string("TWITTER_USERNAME", &mut self.twitter.username);
+        override_string("TWITTER_PASSWORD", &mut self.twitter.password);
+        override_string("TWITTER_EMAIL", &mut self.twitter.email);
+        override_string("TWITTER_2FA_CODE", &mut self.twitter.two_factor_secret);
+        override_string("TWITTER_COOKIE_FILE", &mut self.twitter.cookie_file);
+
+        override_string("OPENAI_API_KEY", &mut self.openai.api_key);
+        override_string("OPENAI_COMPLETION_MODEL", &mut self.openai.completion_model);
+        override_string("OPENAI_IMAGE_MODEL", &mut self.openai.image_model);
+
+        override_string("GOOGLE_SERVICE_ACCOUNT_FILE", &mut self.vision.service_account_file);
+        override_parsed("LEO_DESCRIBE_BANNER", &mut self.vision.describe_banner, problems);
+    }
+
+    // Collect every invalid or missing setting
+    fn validate(&self, scope: ConfigScope, problems: &mut Vec<String>) {
+        require("bot.storage_file", &self.bot.storage_file, problems);
+        if scope == ConfigScope::Storage {
+            return;
+        }
+
+        require("bot.translate_prompt (TRANSLATE_PROMPT)", &self.bot.translate_prompt, problems);
+        require("bot.reply_text (TWITTER_REPLY_TEXT)", &self.bot.reply_text, problems);
+        if self.bot.max_tweets <= 0 {
+            problems.push("bot.max_tweets must be positive".into());
+        }
+        if self.bot.max_pages == 0 {
+            problems.push("bot.max_pages must be positive".into());
+        }
+        if self.bot.min_poll_interval_secs == 0 {
+            problems.push("bot.min_poll_interval_secs must be positive".into());
+        }
+        if self.bot.min_poll_interval_secs > self.bot.poll_interval_secs
+            || self.bot.poll_interval_secs > self.bot.max_poll_backoff_secs
+        {
+            problems.push(
+                "bot.min_poll_interval_secs, poll_interval_secs and max_poll_backoff_secs must be in increasing order".into(),
+            );
+        }
+        if self.bot.workers == 0 {
+            problems.push("bot.workers must be positive".into());
+        }
+        if self.bot.max_attempts == 0 {
+            problems.push("bot.max_attempts must be positive".into());
+        }
+        if self.twitter.max_concurrent == 0 {
+            problems.push("twitter.max_concurrent must be positive".into());
+        }
+
+        require("twitter.username (TWITTER_USERNAME)", &self.twitter.username, problems);
+        require("twitter.password (TWITTER_PASSWORD)", &self.twitter.password, problems);
+        require("twitter.email (TWITTER_EMAIL)", &self.twitter.email, problems);
+
+        require("openai.api_key (OPENAI_API_KEY)", &self.openai.api_key, problems);
+        require("openai.completion_model", &self.openai.completion_model, problems);
+        require("openai.image_model", &self.openai.image_model, problems);
+        if self.openai.image_width == 0 || self.openai.image_height == 0 {
+            problems.push("openai.image_width and openai.image_height must be positive".into());
+        }
+        if self.openai.max_concurrent_completions == 0 || self.openai.max_concurrent_images == 0 {
+            problems.push("openai.max_concurrent_completions and openai.max_concurrent_images must be positive".into());
+        }
+
+        if !Path::new(&self.vision.service_account_file).is_file() {
+            problems.push(format!(
+                "vision.service_account_file {} does not exist",
+                self.vision.service_account_file
+            ));
+        }
+        if self.vision.max_results == 0 {
+            problems.push("vision.max_results must be positive".into());
+        }
+        if self.vision.max_concurrent == 0 {
+            problems.push("vision.max_concurrent must be positive".into());
+        }
+
+        validate_upstream("http.openai", &self.http.openai, problems);
+        validate_upstream("http.openai_completion", &self.http.openai_completion, problems);
+        validate_upstream("http.google_token", &self.http.google_token, problems);
+        validate_upstream("http.vision", &self.http.vision, problems);
+        validate_upstream("http.avatar", &self.http.avatar, problems);
+
+        validate_media("media.upload", &self.media.upload, problems);
+        validate_media("media.describe", &self.media.describe, problems);
+        validate_download(&self.media.download, problems);
+    }
+}
+
+// Record problems of a single media target
+fn validate_media(name: &str, target: &MediaTarget, problems: &mut Vec<String>) {
+    if target.max_bytes == 0 || target.max_dimension == 0 {
+        problems.push(format!("{}.max_bytes and max_dimension must be positive", name));
+    }
+    if !(1..=100).contains(&target.min_quality) {
+        problems.push(format!("{}.min_quality must be between 1 and 100", name));
+    }
+    if target.format == ImageFormat::Gif {
+        problems.push(format!("{}.format must be jpeg, png or webp", name));
+    }
+}
+
+// Record problems of the download limits
+fn validate_download(download: &DownloadConfig, problems: &mut Vec<String>) {
+    if download.allowed_hosts.is_empty() {
+        problems.push("media.download.allowed_hosts must not be empty".into());
+    }
+    for host in &download.allowed_hosts {
+        let valid = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-');
+        if !valid {
+            problems.push(format!("media.download.allowed_hosts entry {:?} must be a lowercase host name", host));
+        }
+    }
+    if download.max_bytes == 0 {
+        problems.push("media.download.max_bytes must be positive".into());
+    }
+}
+
+// Record problems of a single upstream setting
+fn validate_upstream(name: &str, upstream: &UpstreamConfig, problems: &mut Vec<String>) {
+    if upstream.max_attempts == 0 {
+        problems.push(format!("{}.max_attempts must be positive", name));
+    }
+    if upstream.initial_backoff_ms > upstream.max_backoff_ms {
+        problems.push(format!("{}.initial_backoff_ms must not exceed max_backoff_ms", name));
+    }
+    if upstream.connect_timeout_secs == 0 || upstream.request_timeout_secs == 0 {
+        problems.push(format!("{} timeouts must be positive", name));
+    }
+}
+
+// Replace a string setting with an environment variable when it is set
+fn override_string(name: &str, value: &mut String) {
+    if let Ok(env_value) = env::var(name) {
+        *value = env_value;
+    }
+}
+
+// Replace a parsed setting with an environment variable, recording unparsable values
+fn override_parsed<T: FromStr>(name: &str, value: &mut T, problems: &mut Vec<String>) {
+    if let Ok(env_value) = env::var(name) {
+        match env_value.parse() {
+            Ok(parsed) => *value = parsed,
+            Err(_) => problems.push(format!("{} has invalid value {:?}", name, env_value)),
+        }
+    }
+}
+
+// Record a problem when a required setting is empty
+fn require(name: &str, value: &str, problems: &mut Vec<String>) {
+    if value.trim().is_empty() {
+        problems.push(format!("Missing {}", name));
+    }
+}
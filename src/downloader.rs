@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use reqwest::{
+    header::{CONTENT_TYPE, LOCATION},
+    Response, Url,
+};
+use crate::config::{DownloadConfig, UpstreamConfig};
+use crate::error::{LeoError, Result};
+use crate::http_client::{check_status, HttpClient};
+use crate::image::Image;
+use crate::image_format::ImageFormat;
+use crate::pipeline::ImageFetcher;
+
+// Content types accepted for downloaded images
+const IMAGE_FORMATS: [ImageFormat; 4] = [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Gif, ImageFormat::Webp];
+
+// Downloader for images at user controlled URLs, restricted to allowed hosts and bounded in size
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    // Client returning redirects instead of following them, so every hop is checked
+    http_client: HttpClient,
+    // Allowed hosts and size limits
+    config: DownloadConfig,
+}
+
+impl Downloader {
+    // Create a downloader with the timeouts and retries of the upstream
+    pub fn initialize(config: &DownloadConfig, upstream: &UpstreamConfig) -> Result<Self> {
+        Ok(Self {
+            http_client: HttpClient::without_redirects(upstream)?,
+            config: config.clone(),
+        })
+    }
+
+    // Download an image, refusing URLs outside the allowed hosts, other content types and oversized bodies
+    pub async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let mut url = Url::parse(url).map_err(|err| LeoError::DownloadRefused(format!("Invalid URL {} {}", url, err)))?;
+        let mut redirects = 0;
+
+        loop {
+            self.check_url(&url)?;
+            let response = self.http_client.get(url.as_str()).await?;
+            if !response.status().is_redirection() {
+                let response = check_status(response).await.map_err(|err| match err {
+                    // Sizes Twitter never generated or has removed, callers may try another URL
+                    LeoError::Api(api) if matches!(api.status, 404 | 410) => LeoError::BadRequest(api),
+                    err => err,
+                })?;
+                check_content_type(&response)?;
+                return self.read_body(response).await;
+            }
+
+            // Follow the redirect by hand, the target is checked on the next round
+            if redirects >= self.config.max_redirects {
+                return Err(LeoError::DownloadRefused(format!(
+                    "{} redirected more than {} times",
+                    url, self.config.max_redirects
+                )));
+            }
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| LeoError::DownloadRefused(format!("{} redirected without a location", url)))?;
+            url = url
+                .join(location)
+                .map_err(|err| LeoError::DownloadRefused(format!("Invalid redirect {} {}", location, err)))?;
+            redirects += 1;
+        }
+    }
+
+    // Allow HTTPS URLs on the default port of an allowed host or one of its subdomains
+    fn check_url(&self, url: &Url) -> Result<()> {
+        if url.scheme() != "https" || url.port().is_some() {
+            return Err(LeoError::DownloadRefused(format!("{} is not a plain HTTPS URL", url)));
+        }
+
+        // IP addresses never match, which keeps internal addresses out of reach
+        let host = url.domain().unwrap_or_default().trim_end_matches('.');
+        let allowed = self.config.allowed_hosts.iter().any(|allowed| {
+            host == allowed || host.strip_suffix(allowed.as_str()).is_some_and(|sub| sub.ends_with('.'))
+        });
+        if !allowed {
+            return Err(LeoError::DownloadRefused(format!("{} is not an allowed host", url)));
+        }
+
+        Ok(())
+    }
+
+    // Read the body in chunks, stopping as soon as it exceeds the limit
+    async fn read_body(&self, mut response: Response) -> Result<Vec<u8>> {
+        let max_bytes = self.config.max_bytes;
+        let too_large = |url: &Url| LeoError::DownloadRefused(format!("{} is larger than {} bytes", url, max_bytes));
+
+        if response.content_length().is_some_and(|length| length > max_bytes as u64) {
+            return Err(too_large(response.url()));
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > max_bytes {
+                return Err(too_large(response.url()));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(body)
+    }
+}
+
+// Implementation of ImageFetcher trait for avatars and banners
+#[async_trait]
+impl ImageFetcher for Downloader {
+    async fn fetch_image(&self, url: &str) -> Result<Image> {
+        Image::create_from_url(self, url).await
+    }
+}
+
+// Accept only the image types the bot can decode
+fn check_content_type(response: &Response) -> Result<()> {
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    // Drop parameters such as the charset
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+
+    if IMAGE_FORMATS.iter().any(|format| format.mime_type() == essence) {
+        Ok(())
+    } else {
+        Err(LeoError::DownloadRefused(format!(
+            "{} has content type {:?}, expected an image",
+            response.url(),
+            content_type
+        )))
+    }
+}
@@ -0,0 +1,181 @@
+use std::{fmt, io};
+
+use thiserror::Error;
+
+// Result alias used by every public function of the library
+pub type Result<T> = std::result::Result<T, LeoError>;
+
+// Error classes the bot can run into while processing tweets
+#[derive(Debug, Error)]
+pub enum LeoError {
+    // Missing or invalid configuration
+    #[error("Configuration error: {0}")]
+    Config(String),
+    // Credentials were rejected or could not be produced
+    #[error("Authentication error: {0}")]
+    Auth(String),
+    // Upstream HTTP request failed before a response was received
+    #[error("Upstream HTTP error: {0}")]
+    Http(String),
+    // Upstream API answered with an error status
+    #[error("Upstream API error: {0}")]
+    Api(ApiError),
+    // Upstream API refused the request because the account quota is exhausted
+    #[error("Quota exhausted: {0}")]
+    Quota(ApiError),
+    // Upstream API refused the request as malformed
+    #[error("Bad request: {0}")]
+    BadRequest(ApiError),
+    // Twitter scraper call failed
+    #[error("Twitter error: {0}")]
+    Twitter(String),
+    // Upstream throttled the bot, every request fails until the window passes
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+    // Response or payload could not be decoded
+    #[error("Decode error: {0}")]
+    Decode(String),
+    // Request was refused by a provider content policy
+    #[error("Policy rejection: {0}")]
+    PolicyRejection(String),
+    // Download was refused because the URL or response is outside the allowed limits
+    #[error("Download refused: {0}")]
+    DownloadRefused(String),
+    // Persistent state could not be read or written
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+impl LeoError {
+    // Check whether the error must stop the bot instead of being retried later
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            LeoError::Config(_) | LeoError::Auth(_) | LeoError::Quota(_) | LeoError::Storage(_)
+        )
+    }
+
+    // Check whether the error asks to slow down rather than to retry the tweet
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(self, LeoError::RateLimited(_))
+    }
+
+    // Check whether retrying the same tweet can never succeed
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            LeoError::PolicyRejection(_) | LeoError::BadRequest(_) | LeoError::DownloadRefused(_)
+        )
+    }
+
+    // Classify an API error by its status when the provider has no specific rule
+    pub fn from_api_status(api: ApiError) -> Self {
+        match api.status {
+            401 | 403 => LeoError::Auth(api.to_string()),
+            400 | 404 | 413 | 422 => LeoError::BadRequest(api),
+            _ => LeoError::Api(api),
+        }
+    }
+}
+
+// Error response of an upstream API
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    // URL of the failed request
+    pub url: String,
+    // HTTP status code
+    pub status: u16,
+    // Provider error code, e.g. `content_policy_violation` or `RESOURCE_EXHAUSTED`
+    pub code: Option<String>,
+    // Provider error message, or the raw body when it could not be parsed
+    pub message: String,
+}
+
+impl ApiError {
+    // Parse an error body in the OpenAI, Google API or OAuth format
+    pub fn from_body(url: &str, status: u16, body: &str) -> Self {
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+        let error = &payload["error"];
+
+        // OpenAI uses a string `code`, Google a numeric `code` with a string `status`,
+        // OAuth a plain `error` string with an `error_description`
+        let code = error["code"]
+            .as_str()
+            .or_else(|| error["status"].as_str())
+            .or_else(|| error["type"].as_str())
+            .or_else(|| error.as_str())
+            .map(str::to_string);
+        let message = error["message"]
+            .as_str()
+            .or_else(|| payload["error_description"].as_str())
+            .map(str::to_string)
+            .unwrap_or_else(|| body.to_string());
+
+        Self {
+            url: url.to_string(),
+            status,
+            code,
+            message,
+        }
+    }
+
+    // Check whether the provider error code matches
+    pub fn has_code(&self, code: &str) -> bool {
+        self.code.as_deref() == Some(code)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "{} returned {} ({}): {}", self.url, self.status, code, self.message),
+            None => write!(f, "{} returned {}: {}", self.url, self.status, self.message),
+        }
+    }
+}
+
+impl From<reqwest::Error> for LeoError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            LeoError::Decode(err.to_string())
+        } else {
+            LeoError::Http(err.to_string())
+        }
+    }
+}
+
+impl From<io::Error> for LeoError {
+    fn from(err: io::Error) -> Self {
+        LeoError::Storage(err.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for LeoError {
+    fn from(err: rusqlite::Error) -> Self {
+        LeoError::Storage(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for LeoError {
+    fn from(err: serde_json::Error) -> Self {
+        LeoError::Decode(err.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for LeoError {
+    fn from(err: base64::DecodeError) -> Self {
+        LeoError::Decode(err.to_string())
+    }
+}
+
+impl From<image::ImageError> for LeoError {
+    fn from(err: image::ImageError) -> Self {
+        LeoError::Decode(err.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for LeoError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        LeoError::Auth(err.to_string())
+    }
+}
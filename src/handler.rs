@@ -1,161 +1,683 @@
-use std::{env, process};
-use crate::image::{Image, ImageGenerator, ImageRequest};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::{future, stream, StreamExt};
+use log::warn;
+use tokio::time::sleep;
+use crate::config::{Config, MediaConfig};
+use crate::error::{LeoError, Result};
+use crate::downloader::Downloader;
+use crate::image::{Image, ImageRequest};
 use crate::image_gen::ImageGen;
-use crate::storage::Storage;
-use crate::twitter::{ExtractedTweet, Twitter};
-use crate::utils::generate_custom_image_path;
-use crate::vision::{GoogleVision, GoogleVisionRequest};
-use anyhow::Result;
-use log::error;
-use rig::completion::Prompt;
-use rig::providers::openai;
+use crate::image_processing::fit_off_thread;
+use crate::pipeline::{acquire, Limits, Stages};
+use crate::prompt_gen::PromptGen;
+use crate::storage::{Storage, TweetOutput, STALE_MENTION};
+use crate::twitter::{compare_ids, ExtractedTweet, Twitter};
+use crate::utils::{format_date, generate_custom_image_path, now_secs, retention_cutoff};
+use crate::vision::GoogleVision;
+
+// Result of processing a single tweet
+enum Outcome {
+    // Artwork was posted, with references to what was produced
+    Posted(TweetOutput),
+    // Tweet needs no answer, with the reason
+    Skipped(&'static str),
+}
+
+// Summary of a single polling or backfill iteration
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IterationReport {
+    // New mentions returned by the search
+    pub found: usize,
+    // Tweets answered with artwork
+    pub posted: usize,
+    // Tweets needing no answer
+    pub skipped: usize,
+    // Failed attempts, retried later or moved to the dead letters
+    pub failed: usize,
+}
+
+impl fmt::Display for IterationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} new mentions, {} posted, {} skipped, {} failed",
+            self.found, self.posted, self.skipped, self.failed
+        )
+    }
+}
 
 // Main handler struct for processing tweets
 pub struct Handler {
-    translate_prompt: String,
     reply_text: String,
-    // Storage for persisting processed tweet IDs
-    storage: Storage,
-    // Twitter client instance
-    twitter: Twitter,
-    // Maximum number of tweets to process
+    // Size of the generated artwork
+    image_width: u32,
+    image_height: u32,
+    // Storage for persisting processed tweet IDs, shared with the blocking tasks that save statuses
+    storage: Arc<Mutex<Box<dyn Storage>>>,
+    // Size limits of the images sent to the describer and to Twitter
+    media: MediaConfig,
+    // Add the labels of the profile banner to those of the avatar
+    describe_banner: bool,
+    // Pipeline stage implementations
+    stages: Stages,
+    // Per-provider concurrency limits
+    limits: Limits,
+    // Maximum number of tweets per search page
     max_tweets: i32,
+    // Maximum number of search pages per iteration
+    max_pages: u32,
+    // Number of tweets processed in parallel
+    workers: usize,
+    // Failed attempts after which a tweet is moved to the dead letters
+    max_attempts: u32,
+    // Days finished records are kept, 0 keeps them forever
+    retention_days: u64,
+    // Mentions older than this many seconds are skipped, 0 answers every mention
+    max_mention_age_secs: u64,
+    // Pause between search pages while backfilling
+    backfill_page_delay: Duration,
+    // Set once shutdown was requested, no new tweets are started afterwards
+    stopping: AtomicBool,
 }
 
 impl Handler {
-    // Initialize a new Handler instance with storage
-    pub async fn initialize(storage: Storage) -> Result<Self> {
-        let translate_prompt = env::var("TRANSLATE_PROMPT").unwrap_or_else(|err| {
-            error!("Missing TRANSLATE_PROMPT {}", err);
-            process::exit(1);
-        });
-
-        let reply_text = env::var("TWITTER_REPLY_TEXT").unwrap_or_else(|err| {
-            error!("Missing TWITTER_REPLY_TEXT {}", err);
-            process::exit(1);
-        });
-
-        Ok(Self {
-            translate_prompt,
-            reply_text,
-            storage,
-            twitter: Twitter::initialize().await?,
-            max_tweets: 20,
-        })
+    // Initialize a new Handler instance with the default providers
+    pub async fn initialize(config: &Config, storage: Box<dyn Storage>) -> Result<Self> {
+        let twitter = Arc::new(Twitter::initialize(&config.twitter).await?);
+        let stages = Stages {
+            mentions: twitter.clone(),
+            image_fetcher: Arc::new(Downloader::initialize(&config.media.download, &config.http.avatar)?),
+            describer: Arc::new(GoogleVision::initialize(&config.vision, &config.http)?),
+            prompt_writer: Arc::new(PromptGen::initialize(
+                &config.openai,
+                &config.http.openai_completion,
+                &config.bot.translate_prompt,
+            )?),
+            image_generator: Arc::new(ImageGen::initialize(&config.openai, &config.http.openai)?),
+            publisher: twitter.clone(),
+        };
+
+        Ok(Self::with_stages(config, storage, stages))
+    }
+
+    // Create a Handler instance with custom stage implementations
+    pub fn with_stages(config: &Config, storage: Box<dyn Storage>, stages: Stages) -> Self {
+        Self {
+            reply_text: config.bot.reply_text.clone(),
+            image_width: config.openai.image_width,
+            image_height: config.openai.image_height,
+            storage: Arc::new(Mutex::new(storage)),
+            media: config.media.clone(),
+            describe_banner: config.vision.describe_banner,
+            stages,
+            limits: Limits::from_config(config),
+            max_tweets: config.bot.max_tweets,
+            max_pages: config.bot.max_pages,
+            workers: config.bot.workers,
+            max_attempts: config.bot.max_attempts,
+            retention_days: config.bot.retention_days,
+            max_mention_age_secs: config.bot.max_mention_age_hours.saturating_mul(60 * 60),
+            backfill_page_delay: Duration::from_secs(config.bot.backfill_page_delay_secs),
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    // Stop fetching mentions and starting tweets, tweets already started run to completion
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    // Check whether shutdown was requested
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    // Make every recorded status durable
+    pub fn flush(&self) -> Result<()> {
+        self.storage.lock().unwrap().flush()
     }
 
     // Process new tweets mentioning the bot
-    pub async fn handle_tweets(&mut self) -> Result<()> {
-        // Search for tweets mentioning the bot
-        let tweets = self
-            .twitter
-            .find_tweets(&format!("@{}", self.twitter.username), self.max_tweets, None, None)
-            .await?;
+    pub async fn handle_tweets(&self) -> Result<IterationReport> {
+        if self.is_stopping() {
+            return Ok(IterationReport::default());
+        }
+
+        // Search for tweets mentioning the bot since the last recorded one
+        let since_id = self.storage.lock().unwrap().since_id()?;
+        let tweets = self.find_mentions(since_id.as_deref()).await?;
+
+        // Record new tweets, skipping stale ones, and move the high-water mark
+        {
+            let mut storage = self.storage.lock().unwrap();
+            let cutoff = now_secs().saturating_sub(self.max_mention_age_secs);
+            let mut stale = 0;
+            for tweet in &tweets {
+                if storage.track(tweet)? && self.max_mention_age_secs > 0 && is_older_than(tweet, cutoff) {
+                    storage.skip(tweet.id.as_deref().unwrap_or_default(), STALE_MENTION)?;
+                    stale += 1;
+                }
+            }
+            if stale > 0 {
+                println!("Skipped {} mentions older than {} hours", stale, self.max_mention_age_secs / 3600);
+            }
+            let newest = tweets.iter().filter_map(|tweet| tweet.id.as_deref()).max_by(|a, b| compare_ids(a, b));
+            if let Some(newest) = newest {
+                if since_id.as_deref().is_none_or(|since_id| compare_ids(newest, since_id).is_gt()) {
+                    storage.set_since_id(newest)?;
+                }
+            }
+            storage.flush()?;
+            self.prune(storage.as_mut(), &tweets)?;
+        }
+
+        let mut report = self.process_queued().await?;
+        report.found = tweets.len();
+        Ok(report)
+    }
+
+    // Record mentions posted in `[from, until)` regardless of their age and answer them, walking
+    // the search pages at a limited pace
+    pub async fn backfill(&self, from: u64, until: u64) -> Result<IterationReport> {
+        let query = format!(
+            "@{} since:{} until:{}",
+            self.stages.mentions.username(),
+            format_date(from),
+            format_date(until)
+        );
+        let mut cursor = None;
+        let mut recorded = 0;
+
+        loop {
+            let page = {
+                let _permit = acquire(&self.limits.twitter).await?;
+                self.stages.mentions.search(&query, self.max_tweets, cursor).await?
+            };
 
-        // Process each tweet
-        for tweet in &tweets {
-            // Extract tweet ID or skip if none
-            let id = match &tweet.id {
-                Some(id) => id.clone(),
-                None => continue,
+            // The search operators are day based, keep the exact range
+            let in_range = page.tweets.iter().filter(|tweet| {
+                tweet
+                    .timestamp
+                    .is_none_or(|timestamp| (from as i64..until as i64).contains(&timestamp))
+            });
+            {
+                let mut storage = self.storage.lock().unwrap();
+                for tweet in in_range {
+                    if storage.track_backfill(tweet)? {
+                        recorded += 1;
+                    }
+                }
+                storage.flush()?;
+            }
+            println!("Backfill recorded {} mentions so far", recorded);
+
+            cursor = match page.next {
+                Some(next) if !self.is_stopping() => Some(next),
+                _ => break,
             };
+            sleep(self.backfill_page_delay).await;
+        }
+
+        let mut report = self.process_queued().await?;
+        report.found = recorded;
+        Ok(report)
+    }
+
+    // Answer every tweet waiting for a first attempt or a retry
+    async fn process_queued(&self) -> Result<IterationReport> {
+        let queued = self.storage.lock().unwrap().retryable()?;
 
-            // Skip if tweet was already processed
-            if self.storage.exists(id.clone()) {
-                println!("Tweet {} already processed. Skipping", id.clone());
-                continue;
+        // Keep each unprocessed tweet once, even if it was queued twice
+        let mut seen = HashSet::new();
+        let mut pending = Vec::new();
+        for tweet in queued {
+            if let Some(id) = tweet.id.clone() {
+                if seen.insert(id.clone()) {
+                    pending.push((id, tweet));
+                }
             }
+        }
+
+        // Process tweets on a bounded number of workers, stop scheduling after a fatal error or
+        // once shutdown was requested
+        let stop = AtomicBool::new(false);
+        let mut results = stream::iter(pending)
+            .take_while(|_| future::ready(!stop.load(Ordering::SeqCst) && !self.is_stopping()))
+            .map(|(id, tweet)| async move {
+                self.mark_started(&id).await?;
+                let result = self.process_single_tweet(&id, &tweet).await;
+                Ok::<_, LeoError>((id, result))
+            })
+            .buffer_unordered(self.workers);
 
-            // Handle tweet and track processed status
-            if let Err(e) = self.process_single_tweet(tweet).await {
-                println!("Error processing tweet {}: {:?}", id, e);
-            } else {
-                // Store processed tweet ID and save to file
-                self.storage.add(id);
-                self.storage.write_to_file()?;
+        let mut report = IterationReport::default();
+        let mut fatal = None;
+        while let Some(next) = results.next().await {
+            let (id, result) = next?;
+            let max_attempts = self.max_attempts;
+
+            // Handle tweet and track processed status, each new status is made durable
+            match result {
+                Ok(Outcome::Posted(output)) => {
+                    self.save(move |storage| storage.complete(&id, output)).await?;
+                    report.posted += 1;
+                }
+                Ok(Outcome::Skipped(reason)) => {
+                    println!("Tweet {} skipped: {}", id, reason);
+                    self.save(move |storage| storage.skip(&id, reason)).await?;
+                    report.skipped += 1;
+                }
+                // Configuration and credential problems affect every tweet and throttling affects every
+                // following request, let in-flight work finish and stop without counting an attempt
+                Err(e) if e.is_fatal() || e.is_rate_limited() => {
+                    println!("Stopped processing at tweet {}: {}", id, e);
+                    stop.store(true, Ordering::SeqCst);
+                    self.save(move |storage| storage.release(&id)).await?;
+                    fatal.get_or_insert(e);
+                }
+                // Failures are retried on later iterations until the attempt budget is spent
+                Err(e) => {
+                    println!("Error processing tweet {}: {:?}", id, e);
+                    self.save(move |storage| storage.fail(&id, &e, max_attempts)).await?;
+                    report.failed += 1;
+                }
             }
         }
 
-        Ok(())
+        match fatal {
+            Some(e) => Err(e),
+            None => Ok(report),
+        }
     }
 
-    // Handle individual tweet processing
-    async fn process_single_tweet(&self, tweet: &ExtractedTweet) -> Result<()> {
-        // Get user profile information
-        let profile = self
-            .twitter
-            .fetch_profile(tweet.username.clone().unwrap().as_str())
-            .await?;
+    // Page through mentions newer than the high-water mark. Without one only the first page is read,
+    // older mentions predate the bot
+    async fn find_mentions(&self, since_id: Option<&str>) -> Result<Vec<ExtractedTweet>> {
+        let query = format!("@{}", self.stages.mentions.username());
+        let mut tweets = Vec::new();
+        let mut cursor = None;
+
+        for _ in 0..self.max_pages {
+            let page = {
+                let _permit = acquire(&self.limits.twitter).await?;
+                self.stages.mentions.search(&query, self.max_tweets, cursor).await?
+            };
+
+            let Some(since_id) = since_id else {
+                return Ok(page.tweets);
+            };
 
-        // Skip if tweet is from the bot itself
-        if profile.username == self.twitter.username {
-            println!("Username is self. Skipping");
+            // Stop at the first page reaching back to the high-water mark
+            let reached = page.tweets.iter().any(|tweet| !tweet.is_newer_than(since_id));
+            tweets.extend(page.tweets.into_iter().filter(|tweet| tweet.is_newer_than(since_id)));
+            if reached {
+                return Ok(tweets);
+            }
+
+            cursor = match page.next {
+                Some(next) => Some(next),
+                None => return Ok(tweets),
+            };
+        }
+
+        println!(
+            "Mentions since {} exceed {} pages, older ones in between are skipped",
+            since_id.unwrap_or_default(),
+            self.max_pages
+        );
+        Ok(tweets)
+    }
+
+    // Expire finished records past the retention window that the search no longer returns
+    fn prune(&self, storage: &mut dyn Storage, tweets: &[ExtractedTweet]) -> Result<()> {
+        if self.retention_days == 0 {
             return Ok(());
         }
 
-        // Get user's avatar URL
-        let avatar_url = match self.twitter.fetch_avatar(profile).await? {
-            Some(url) => url,
-            None => {
-                println!("Avatar not found. Skipping");
-                return Ok(());
+        let cutoff = retention_cutoff(self.retention_days);
+        let horizon: HashSet<String> = tweets.iter().filter_map(|tweet| tweet.id.clone()).collect();
+        let report = storage.prune(cutoff, &horizon)?;
+        if !report.removed.is_empty() {
+            println!("Retention: {}", report);
+        }
+
+        Ok(())
+    }
+
+    // Mark a tweet as picked up by a worker and save it
+    async fn mark_started(&self, id: &str) -> Result<()> {
+        let id = id.to_string();
+        self.save(move |storage| storage.start(&id)).await
+    }
+
+    // Apply a status change and flush it on the blocking thread pool. The JSON backend rewrites and
+    // syncs the whole file on every flush, which would stall the other workers of this thread
+    async fn save(&self, change: impl FnOnce(&mut dyn Storage) -> Result<()> + Send + 'static) -> Result<()> {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut storage = storage.lock().unwrap();
+            change(storage.as_mut())?;
+            storage.flush()
+        })
+        .await
+        .map_err(|err| LeoError::Storage(format!("Saving the tweet status failed {}", err)))?
+    }
+
+    // Handle individual tweet processing, resuming after the last stage an earlier attempt completed
+    async fn process_single_tweet(&self, id: &str, tweet: &ExtractedTweet) -> Result<Outcome> {
+        let mut output = self.checkpoints(id)?;
+        let mut image = None;
+
+        if output.image_path.is_none() {
+            if output.prompt.is_none() {
+                if output.labels.is_none() {
+                    // Get user profile information
+                    let username = tweet
+                        .username
+                        .as_deref()
+                        .ok_or_else(|| LeoError::Decode("Tweet has no username".into()))?;
+                    let profile = {
+                        let _permit = acquire(&self.limits.twitter).await?;
+                        self.stages.mentions.lookup_profile(username).await?
+                    };
+
+                    // Skip if tweet is from the bot itself
+                    if profile.username == self.stages.mentions.username() {
+                        return Ok(Outcome::Skipped("Username is self"));
+                    }
+
+                    // Get user's avatar and banner URLs
+                    let images = match profile.images {
+                        Some(images) => images,
+                        None => return Ok(Outcome::Skipped("Avatar not found")),
+                    };
+
+                    // Describe the avatar
+                    let avatar = self.download_avatar(&images.avatars).await?;
+                    let mut labels = self.describe(avatar).await?;
+
+                    // Add what the banner shows, a failure only loses the extra labels
+                    if let Some(banner_url) = images.banner.as_deref().filter(|_| self.describe_banner) {
+                        let banner = match self.stages.image_fetcher.fetch_image(banner_url).await {
+                            Ok(banner) => self.describe(banner).await,
+                            Err(err) => Err(err),
+                        };
+                        match banner {
+                            Ok(extra) => {
+                                for label in extra {
+                                    if !labels.contains(&label) {
+                                        labels.push(label);
+                                    }
+                                }
+                            }
+                            Err(err) if err.is_fatal() || err.is_rate_limited() => return Err(err),
+                            Err(err) => warn!("Ignoring banner of {}: {}", username, err),
+                        }
+                    }
+                    output.labels = Some(labels);
+                    self.checkpoint(id, &output).await?;
+                }
+
+                // Rewrite the labels into an image prompt
+                let description = output.labels.as_deref().unwrap_or_default().join(",");
+                let prompt = {
+                    let _permit = acquire(&self.limits.completions).await?;
+                    self.stages.prompt_writer.write_prompt(&description).await?
+                };
+                output.prompt = Some(prompt);
+                self.checkpoint(id, &output).await?;
             }
-        };
 
-        // Process image and generate response
-        let image = Image::create_from_url(&avatar_url)?;
-        let description = self.create_description(image)?;
-        let translated_desc = self.convert_description(&description).await?;
-        let image = self.produce_image(&translated_desc)?;
+            // Generate the artwork
+            let (generated, image_path) = self.produce_image(output.prompt.as_deref().unwrap_or_default()).await?;
+            image = Some(generated);
+            output.image_path = Some(image_path);
+            self.checkpoint(id, &output).await?;
+        }
+
+        // Reuse the artwork an earlier attempt already paid for
+        let image = match image {
+            Some(image) => image,
+            None => Image::create_from_file(output.image_path.clone().unwrap_or_default())?,
+        };
 
         // Send response tweet with generated image
-        self.tweet_with_image(tweet, &image).await?;
+        output.reply_id = Some(self.tweet_with_image(tweet, &image).await?);
 
-        Ok(())
+        Ok(Outcome::Posted(output))
+    }
+
+    // Download the largest avatar size Twitter still serves
+    async fn download_avatar(&self, urls: &[String]) -> Result<Image> {
+        let mut last_error = LeoError::Decode("Profile has no avatar URL".into());
+        for url in urls {
+            match self.stages.image_fetcher.fetch_image(url).await {
+                Ok(image) => return Ok(image),
+                // Sizes that were never generated answer 404 as a bad request, try the next smaller one
+                Err(err) if err.is_permanent() || matches!(err, LeoError::Decode(_)) => {
+                    warn!("Avatar {} unavailable: {}", url, err);
+                    last_error = err;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(last_error)
     }
 
-    // Generate description using Google Vision API
-    fn create_description(&self, image: Image) -> Result<String> {
-        let vision = GoogleVision::initialize()?;
-        let descs = vision.generate_description(GoogleVisionRequest { image, max_results: 10 })?;
-        Ok(descs.join(","))
+    // Shrink an image to the describer limits and label it
+    async fn describe(&self, image: Image) -> Result<Vec<String>> {
+        let image = fit_off_thread(image, self.media.describe).await?;
+        let _permit = acquire(&self.limits.vision).await?;
+        self.stages.describer.describe(image).await
     }
 
-    // Translate and optimize description using GPT-4
-    async fn convert_description(&self, desc_string: &str) -> Result<String> {
-        let client = openai::Client::from_env();
-        let gpt4 = client.agent("gpt-4").build();
-        let prompt_string = self.translate_prompt.replace("{}", &desc_string);
-        let response: String = gpt4.prompt(&prompt_string).await?;
+    // Load the stage outputs of earlier attempts, dropping an image that is no longer on disk
+    fn checkpoints(&self, id: &str) -> Result<TweetOutput> {
+        let mut output = self
+            .storage
+            .lock()
+            .unwrap()
+            .get(id)?
+            .map(|record| record.output)
+            .unwrap_or_default();
+
+        if let Some(image_path) = &output.image_path {
+            if !Path::new(image_path).is_file() {
+                println!("Saved image {} of tweet {} is gone, generating a new one", image_path, id);
+                output.image_path = None;
+            }
+        }
+
+        Ok(output)
+    }
 
-        Ok(response)
+    // Save the stage outputs so far
+    async fn checkpoint(&self, id: &str, output: &TweetOutput) -> Result<()> {
+        let (id, output) = (id.to_string(), output.clone());
+        self.save(move |storage| storage.checkpoint(&id, output)).await
     }
 
-    // Generate new image using DALL-E
-    fn produce_image(&self, description: &str) -> Result<Image> {
-        let image_gen = ImageGen::initialize()?;
-        let image = image_gen.produce_image(ImageRequest {
-            description: description.into(),
-            width: 1792,
-            height: 1024,
-        })?;
+    // Generate new image and save it to disk, returns the image and where it was saved
+    async fn produce_image(&self, description: &str) -> Result<(Image, String)> {
+        let _permit = acquire(&self.limits.images).await?;
+        let image = self
+            .stages
+            .image_generator
+            .produce_image(ImageRequest {
+                description: description.into(),
+                width: self.image_width,
+                height: self.image_height,
+            })
+            .await?;
 
         // Save generated image to disk
-        let output_path = generate_custom_image_path();
+        let output_path = generate_custom_image_path(image.format.extension())?;
         image.store(&output_path)?;
         println!("Saved image to {:?}", output_path);
 
-        Ok(image)
+        Ok((image, output_path.to_string_lossy().into_owned()))
+    }
+
+    // Send tweet with generated image as reply, returns the ID of the posted tweet
+    async fn tweet_with_image(&self, tweet: &ExtractedTweet, image: &Image) -> Result<String> {
+        let username = tweet
+            .username
+            .as_deref()
+            .ok_or_else(|| LeoError::Decode("Tweet has no username".into()))?;
+        let reply_text = self.reply_text.replace("{}", username);
+        // The full size artwork stays on disk, only the upload is shrunk
+        let image = fit_off_thread(image.clone(), self.media.upload).await?;
+        let posted = {
+            let _permit = acquire(&self.limits.twitter).await?;
+            self.stages.publisher.publish(tweet, &reply_text, &image).await?
+        };
+
+        println!("Posted tweet {} as {:?}", posted.id, posted.mode);
+        Ok(posted.id)
     }
+}
 
-    // Send tweet with generated image as reply
-    async fn tweet_with_image(&self, tweet: &ExtractedTweet, image: &Image) -> anyhow::Result<()> {
-        let media_data = vec![(image.to_bytes(), "image/jpeg".to_string())];
+// Check whether a tweet was posted before the cutoff, tweets without a timestamp count as recent
+fn is_older_than(tweet: &ExtractedTweet, cutoff: u64) -> bool {
+    tweet.timestamp.is_some_and(|timestamp| timestamp < cutoff as i64)
+}
 
-        let reply_text = self.reply_text.replace("{}", tweet.username.clone().unwrap().as_str());
-        let tweet_with_media = self.twitter.post_tweet(&reply_text, None, Some(media_data)).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use async_trait::async_trait;
+    use crate::image::ImageGenerator;
+    use crate::memory_storage::MemoryStorage;
+    use crate::pipeline::{AvatarDescriber, ImageFetcher, MentionSource, PromptWriter, Publisher};
+    use crate::twitter::{DeliveryMode, PostedTweet, ProfileImages, TweetPage, UserProfile};
 
-        println!("tweet_with_media {:#?}", tweet_with_media);
-        Ok(())
+    // Smallest valid PNG, a single pixel
+    const PIXEL_PNG: &str =
+        "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==";
+
+    // Stages answering a single mention, counting the calls of each stage. The first publish fails
+    #[derive(Default)]
+    struct FakeStages {
+        described: AtomicUsize,
+        prompted: AtomicUsize,
+        generated: AtomicUsize,
+        published: AtomicUsize,
+    }
+
+    fn pixel() -> Result<Image> {
+        Image::create_from_base64(PIXEL_PNG)
+    }
+
+    #[async_trait]
+    impl MentionSource for FakeStages {
+        fn username(&self) -> &str {
+            "leo"
+        }
+
+        async fn search(&self, _query: &str, _max_tweets: i32, _cursor: Option<String>) -> Result<TweetPage> {
+            let tweet = ExtractedTweet {
+                id: Some("100".into()),
+                username: Some("someone".into()),
+                ..Default::default()
+            };
+            Ok(TweetPage {
+                tweets: vec![tweet],
+                next: None,
+            })
+        }
+
+        async fn lookup_profile(&self, username: &str) -> Result<UserProfile> {
+            Ok(UserProfile {
+                username: username.into(),
+                images: Some(ProfileImages {
+                    avatars: vec!["https://pbs.twimg.com/avatar.png".into()],
+                    banner: None,
+                }),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl ImageFetcher for FakeStages {
+        async fn fetch_image(&self, _url: &str) -> Result<Image> {
+            pixel()
+        }
+    }
+
+    #[async_trait]
+    impl AvatarDescriber for FakeStages {
+        async fn describe(&self, _image: Image) -> Result<Vec<String>> {
+            self.described.fetch_add(1, Ordering::SeqCst);
+            Ok(vec!["lion".into()])
+        }
+    }
+
+    #[async_trait]
+    impl PromptWriter for FakeStages {
+        async fn write_prompt(&self, description: &str) -> Result<String> {
+            self.prompted.fetch_add(1, Ordering::SeqCst);
+            Ok(format!("A painting of {}", description))
+        }
+    }
+
+    #[async_trait]
+    impl ImageGenerator for FakeStages {
+        async fn produce_image(&self, _request: ImageRequest) -> Result<Image> {
+            self.generated.fetch_add(1, Ordering::SeqCst);
+            pixel()
+        }
+    }
+
+    #[async_trait]
+    impl Publisher for FakeStages {
+        async fn publish(&self, _tweet: &ExtractedTweet, _text: &str, _image: &Image) -> Result<PostedTweet> {
+            if self.published.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(LeoError::Twitter("Request failed with status: 500".into()));
+            }
+            Ok(PostedTweet {
+                id: "200".into(),
+                mode: DeliveryMode::Reply,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_from_checkpoint_after_failed_publish() {
+        let fake = Arc::new(FakeStages::default());
+        let stages = Stages {
+            mentions: fake.clone(),
+            image_fetcher: fake.clone(),
+            describer: fake.clone(),
+            prompt_writer: fake.clone(),
+            image_generator: fake.clone(),
+            publisher: fake.clone(),
+        };
+        let handler = Handler::with_stages(&Config::default(), Box::new(MemoryStorage::new()), stages);
+
+        let report = handler.handle_tweets().await.unwrap();
+        assert_eq!((report.posted, report.failed), (0, 1));
+        let record = handler.storage.lock().unwrap().get("100").unwrap().unwrap();
+        assert_eq!(record.attempts, 1);
+        assert_eq!(record.output.prompt.as_deref(), Some("A painting of lion"));
+        assert!(record.output.image_path.is_some());
+
+        // The retry only publishes the saved image
+        let report = handler.handle_tweets().await.unwrap();
+        assert_eq!((report.posted, report.failed), (1, 0));
+        assert_eq!(fake.described.load(Ordering::SeqCst), 1);
+        assert_eq!(fake.prompted.load(Ordering::SeqCst), 1);
+        assert_eq!(fake.generated.load(Ordering::SeqCst), 1);
+        assert_eq!(fake.published.load(Ordering::SeqCst), 2);
+
+        let record = handler.storage.lock().unwrap().get("100").unwrap().unwrap();
+        assert_eq!(record.output.reply_id.as_deref(), Some("200"));
+        std::fs::remove_file(record.output.image_path.unwrap()).ok();
     }
-}
\ No newline at end of file
+}
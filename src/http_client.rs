@@ -1,27 +1,179 @@
-use anyhow::Result;
-use ureq::post;
+use std::{
+    future::Future,
+    time::{Duration, SystemTime},
+};
+use log::warn;
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, redirect::Policy, Client, RequestBuilder, Response, StatusCode};
+use tokio::time::{sleep, timeout};
+use crate::config::UpstreamConfig;
+use crate::error::{ApiError, LeoError, Result};
 
 // HTTP client structure for making requests
-#[derive(Debug)]
-pub struct HttpClient;
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    // Shared async client with its connection pool
+    client: Client,
+    // Retry and timeout settings of the upstream
+    config: UpstreamConfig,
+}
 
 impl HttpClient {
-    // Create new HTTP client instance
-    pub fn initialize() -> Self {
-        HttpClient
+    // Create new HTTP client instance for an upstream
+    pub fn initialize(config: &UpstreamConfig) -> Result<Self> {
+        Self::build(config, Policy::default())
+    }
+
+    // Create an HTTP client returning redirects to the caller instead of following them
+    pub fn without_redirects(config: &UpstreamConfig) -> Result<Self> {
+        Self::build(config, Policy::none())
+    }
+
+    // Build the client with the timeouts of the upstream
+    fn build(config: &UpstreamConfig, redirects: Policy) -> Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+            .timeout(Duration::from_secs(config.request_timeout_secs))
+            .redirect(redirects)
+            .build()
+            .map_err(|err| LeoError::Config(format!("Unable to build HTTP client {}", err)))?;
+
+        Ok(HttpClient { client, config: *config })
     }
 
     // Make POST request with JSON body
-    pub fn send_post(&self, url: &str, body: serde_json::Value) -> Result<serde_json::Value> {
-        let response = post(url).send_json(body)?;
-        Ok(response.into_json()?)
+    pub async fn send_post(&self, url: &str, body: serde_json::Value) -> Result<serde_json::Value> {
+        let response = self.send_with_retry(true, || self.client.post(url).json(&body)).await?;
+        Ok(check_status(response).await?.json().await?)
     }
 
     // Make authenticated POST request with JSON body
-    pub fn send_post_with_auth(&self, url: &str, access_token: &str, body: serde_json::Value) -> Result<String> {
-        let response = post(url)
-            .set("Authorization", &format!("Bearer {}", access_token))
-            .send_json(body)?;
-        Ok(response.into_string()?)
+    pub async fn send_post_with_auth(&self, url: &str, access_token: &str, body: serde_json::Value) -> Result<String> {
+        self.post_with_auth(true, url, access_token, body).await
+    }
+
+    // Make authenticated POST request with JSON body that must not run twice, such as a paid
+    // generation. A timeout may hit after the upstream started the work, so only failures to
+    // connect and error statuses are retried
+    pub async fn send_unrepeatable_post_with_auth(
+        &self,
+        url: &str,
+        access_token: &str,
+        body: serde_json::Value,
+    ) -> Result<String> {
+        self.post_with_auth(false, url, access_token, body).await
+    }
+
+    // Send an authenticated POST request and read the body of a successful response
+    async fn post_with_auth(
+        &self,
+        retry_timeouts: bool,
+        url: &str,
+        access_token: &str,
+        body: serde_json::Value,
+    ) -> Result<String> {
+        let response = self
+            .send_with_retry(retry_timeouts, || self.client.post(url).bearer_auth(access_token).json(&body))
+            .await?;
+        Ok(check_status(response).await?.text().await?)
+    }
+
+    // Make GET request and return the response whatever its status, the body is not read yet
+    pub async fn get(&self, url: &str) -> Result<Response> {
+        self.send_with_retry(true, || self.client.get(url)).await
+    }
+
+    // Send a request, retrying transient failures with exponential backoff. Timeouts are retried
+    // only when repeating the request is harmless
+    async fn send_with_retry(&self, retry_timeouts: bool, build: impl Fn() -> RequestBuilder) -> Result<Response> {
+        let max_backoff = Duration::from_millis(self.config.max_backoff_ms);
+        let mut attempt = 1;
+
+        loop {
+            let outcome = build().send().await;
+
+            // Decide whether the outcome is worth another attempt
+            let retry_after = match &outcome {
+                Ok(response) if is_transient(response.status()) => retry_after(response),
+                Err(err) if err.is_connect() || (retry_timeouts && err.is_timeout()) => None,
+                _ => return Ok(outcome?),
+            };
+
+            let delay = retry_after.unwrap_or_else(|| backoff(&self.config, attempt));
+            if attempt >= self.config.max_attempts || delay > max_backoff {
+                return Ok(outcome?);
+            }
+
+            match &outcome {
+                Ok(response) => warn!("{} returned {}, retrying in {:?}", response.url(), response.status(), delay),
+                Err(err) => warn!("Request failed: {}, retrying in {:?}", err, delay),
+            }
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+}
+
+// Run a request made by a client other than HttpClient with the same settings, bounding every
+// attempt by the request timeout and retrying timeouts, transport failures and rate limits
+pub async fn with_retry<T, F: Future<Output = Result<T>>>(config: &UpstreamConfig, call: impl Fn() -> F) -> Result<T> {
+    let request_timeout = Duration::from_secs(config.request_timeout_secs);
+    let mut attempt = 1;
+
+    loop {
+        let outcome = match timeout(request_timeout, call()).await {
+            Ok(outcome) => outcome,
+            Err(_) => Err(LeoError::Http(format!("No response within {:?}", request_timeout))),
+        };
+
+        match outcome {
+            Err(err @ (LeoError::Http(_) | LeoError::RateLimited(_))) if attempt < config.max_attempts => {
+                let delay = backoff(config, attempt);
+                warn!("{}, retrying in {:?}", err, delay);
+                sleep(delay).await;
+                attempt += 1;
+            }
+            outcome => return outcome,
+        }
     }
-}
\ No newline at end of file
+}
+
+// Compute the jittered delay before the next attempt
+fn backoff(config: &UpstreamConfig, attempt: u32) -> Duration {
+    let exponential = config
+        .initial_backoff_ms
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(config.max_backoff_ms);
+    // Wait between half and the full exponential delay
+    let jittered = rand::thread_rng().gen_range(exponential / 2..=exponential);
+    Duration::from_millis(jittered)
+}
+
+// Check whether a status is worth retrying
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Parse the Retry-After header, either delta seconds or an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+// Turn non-success responses into errors carrying the parsed error payload
+pub async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let url = response.url().to_string();
+    let body = response.text().await.unwrap_or_default();
+    Err(LeoError::Api(ApiError::from_body(&url, status.as_u16(), &body)))
+}
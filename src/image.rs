@@ -1,60 +1,86 @@
+use async_trait::async_trait;
 use base64::{engine::general_purpose, Engine};
-use serde::{Deserialize, Serialize};
-use std::io::Read;
-use std::{
-    fs::{self, File},
-    io::Write,
-    path::PathBuf,
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fs, path::PathBuf, sync::Arc};
+use crate::{
+    downloader::Downloader,
+    error::Result,
+    image_format::{ImageFormat, ImageInfo},
 };
-use ureq::get;
-use anyhow::Result;
 
-// Structure representing an image with base64 encoding
+// Structure representing an encoded image, clones share the data
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Image {
-    // Base64 encoded image data
-    pub base64: String,
+    // Encoded image data, serialized as base64
+    #[serde(rename = "base64", serialize_with = "serialize_base64", deserialize_with = "deserialize_base64")]
+    bytes: Arc<[u8]>,
+    // Encoding detected from the data
+    pub format: ImageFormat,
+    // Width in pixels
+    pub width: u32,
+    // Height in pixels
+    pub height: u32,
 }
 
 impl Image {
+    // Create Image from raw bytes, rejecting data that is not a supported image
+    pub fn create_from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let info = ImageInfo::inspect(&bytes)?;
+
+        Ok(Self {
+            bytes: bytes.into(),
+            format: info.format,
+            width: info.width,
+            height: info.height,
+        })
+    }
+
     // Create Image from base64 string
-    pub fn create_from_base64(base64: String) -> Self {
-        Self { base64 }
+    pub fn create_from_base64(base64: &str) -> Result<Self> {
+        Self::create_from_bytes(general_purpose::STANDARD.decode(base64)?)
     }
 
     // Create Image from file path
-    pub fn create_from_file(file: String) -> Self {
-        let image_data = fs::read(file).unwrap();
-        let base64 = general_purpose::STANDARD.encode(&image_data);
-
-        Self { base64 }
+    pub fn create_from_file(file: String) -> Result<Self> {
+        Self::create_from_bytes(fs::read(file)?)
     }
 
     // Create Image from URL
-    pub fn create_from_url(url: &str) -> Result<Self> {
-        let response = get(url).call()?;
-        let image_bytes = response.into_reader().bytes().collect::<Result<Vec<u8>, _>>()?;
-        let base64 = general_purpose::STANDARD.encode(&image_bytes);
-
-        Ok(Self { base64 })
+    pub async fn create_from_url(downloader: &Downloader, url: &str) -> Result<Self> {
+        Self::create_from_bytes(downloader.download(url).await?)
     }
 
     // Save image to file system
     pub fn store(&self, path: impl Into<PathBuf>) -> Result<()> {
-        let mut file = File::create(path.into()).expect("Failed to create file");
-        file.write_all(&self.to_bytes()).expect("Failed save image");
-
+        fs::write(path.into(), &self.bytes)?;
         Ok(())
     }
 
-    // Get raw bytes from base64 encoded image
-    pub fn to_bytes(&self) -> Vec<u8> {
-        general_purpose::STANDARD
-            .decode(&self.base64)
-            .expect("Failed to decode base64 string")
+    // Get the encoded image data
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Encode the image data as base64 for APIs taking images inline
+    pub fn to_base64(&self) -> String {
+        general_purpose::STANDARD.encode(&self.bytes)
     }
 }
 
+// Write the image data as a base64 string
+fn serialize_base64<S: Serializer>(bytes: &Arc<[u8]>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&general_purpose::STANDARD.encode(bytes))
+}
+
+// Read the image data from a base64 string
+fn deserialize_base64<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Arc<[u8]>, D::Error> {
+    let base64 = String::deserialize(deserializer)?;
+    general_purpose::STANDARD
+        .decode(base64)
+        .map(Arc::from)
+        .map_err(de::Error::custom)
+}
+
 // Structure for image generation request
 pub struct ImageRequest {
     // Description for image generation
@@ -66,7 +92,8 @@ pub struct ImageRequest {
 }
 
 // Trait for image generation functionality
-pub trait ImageGenerator {
+#[async_trait]
+pub trait ImageGenerator: Send + Sync {
     // Create image from request parameters
-    fn produce_image(&self, request: ImageRequest) -> Result<Image>;
+    async fn produce_image(&self, request: ImageRequest) -> Result<Image>;
 }
\ No newline at end of file
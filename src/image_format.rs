@@ -0,0 +1,87 @@
+use std::io::Cursor;
+use image::ImageReader;
+use serde::{Deserialize, Serialize};
+use crate::error::{LeoError, Result};
+
+// Encodings recognised from the leading bytes of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    // Portable Network Graphics, returned by DALL-E
+    Png,
+    // JPEG, used by Twitter avatars
+    Jpeg,
+    // Graphics Interchange Format
+    Gif,
+    // WebP, lossy or lossless
+    Webp,
+}
+
+// Format and size read from an image header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    // Detected encoding
+    pub format: ImageFormat,
+    // Width in pixels
+    pub width: u32,
+    // Height in pixels
+    pub height: u32,
+}
+
+impl ImageFormat {
+    // Detect the format from the magic bytes at the start of the data
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        match image::guess_format(bytes).ok()? {
+            image::ImageFormat::Png => Some(ImageFormat::Png),
+            image::ImageFormat::Jpeg => Some(ImageFormat::Jpeg),
+            image::ImageFormat::Gif => Some(ImageFormat::Gif),
+            image::ImageFormat::WebP => Some(ImageFormat::Webp),
+            _ => None,
+        }
+    }
+
+    // MIME type used when uploading the image
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+
+    // File extension used when saving the image
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    // Matching format of the image crate
+    fn codec(&self) -> image::ImageFormat {
+        match self {
+            ImageFormat::Png => image::ImageFormat::Png,
+            ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+            ImageFormat::Gif => image::ImageFormat::Gif,
+            ImageFormat::Webp => image::ImageFormat::WebP,
+        }
+    }
+}
+
+impl ImageInfo {
+    // Read format and size from the header, rejecting data that is not a well formed image
+    pub fn inspect(bytes: &[u8]) -> Result<Self> {
+        let format = ImageFormat::sniff(bytes)
+            .ok_or_else(|| LeoError::Decode("Data is not a PNG, JPEG, GIF or WebP image".into()))?;
+        let dimensions = ImageReader::with_format(Cursor::new(bytes), format.codec()).into_dimensions();
+
+        match dimensions {
+            Ok((width, height)) if width > 0 && height > 0 => Ok(Self { format, width, height }),
+            Ok(_) => Err(LeoError::Decode(format!("Empty {:?} image", format))),
+            Err(err) => Err(LeoError::Decode(format!("Truncated or malformed {:?} image {}", format, err))),
+        }
+    }
+}
@@ -1,12 +1,12 @@
 use crate::{
+    config::{OpenAiConfig, UpstreamConfig},
+    error::{LeoError, Result},
     http_client::HttpClient,
     image::{Image, ImageGenerator, ImageRequest},
 };
-use anyhow::Result;
-use log::error;
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use ureq::json;
-use std::{env, process};
+use serde_json::json;
 
 // OpenAI API endpoint for image generation
 const OPENAI_IMAGE_GEN_URL: &'static str = "https://api.openai.com/v1/images/generations";
@@ -27,54 +27,70 @@ pub struct ImageData {
     pub b64_json: String,
 }
 
+// Map OpenAI error payloads to the matching error class
+fn classify(err: LeoError) -> LeoError {
+    match err {
+        LeoError::Api(api) if api.has_code("content_policy_violation") => LeoError::PolicyRejection(api.message),
+        LeoError::Api(api) if api.has_code("insufficient_quota") || api.has_code("billing_hard_limit_reached") => {
+            LeoError::Quota(api)
+        }
+        // Still throttled after the HTTP retries, the schedule backs off without spending an attempt
+        LeoError::Api(api) if api.status == 429 => LeoError::RateLimited(api.to_string()),
+        LeoError::Api(api) => LeoError::from_api_status(api),
+        err => err,
+    }
+}
+
 // Main image generation client
 pub struct ImageGen {
     // OpenAI API key
     key: String,
+    // Image model name
+    model: String,
+    // Requested image quality
+    quality: String,
     // HTTP client instance
     http_client: HttpClient,
 }
 
 impl ImageGen {
     // Initialize new image generation client
-    pub fn initialize() -> Result<Self> {
-        // Get OpenAI API key from environment variables
-        let key = env::var("OPENAI_API_KEY").unwrap_or_else(|err| {
-            error!("Missing OPENAI_API_KEY {}", err);
-            process::exit(1);
-        });
-
+    pub fn initialize(config: &OpenAiConfig, http: &UpstreamConfig) -> Result<Self> {
         Ok(Self {
-            key,
-            http_client: HttpClient::initialize(),
+            key: config.api_key.clone(),
+            model: config.image_model.clone(),
+            quality: config.image_quality.clone(),
+            http_client: HttpClient::initialize(http)?,
         })
     }
 }
 
 // Implementation of ImageGenerator trait for DALL-E
+#[async_trait]
 impl ImageGenerator for ImageGen {
     // Create image using DALL-E model
-    fn produce_image(&self, request: ImageRequest) -> Result<Image> {
+    async fn produce_image(&self, request: ImageRequest) -> Result<Image> {
         // Make request to OpenAI API
-        let response = self.http_client.send_post_with_auth(
+        let response = self.http_client.send_unrepeatable_post_with_auth(
             OPENAI_IMAGE_GEN_URL,
             &self.key,
             json!({
               "prompt": request.description,
               "n": 1,                           // Generate one image
               "response_format": "b64_json",    // Request base64 encoded response
-              "model": "dall-e-3",             // Use DALL-E 3 model
-              "quality": "hd",                 // Request high quality image
+              "model": self.model,              // Configured image model
+              "quality": self.quality,          // Configured image quality
               "size": format!("{}x{}", request.width, request.height), // Set image dimensions
             }),
-        )?;
+        ).await.map_err(classify)?;
 
         // Parse response and extract image data
         let images: Images = serde_json::from_str(&response)?;
-        let image = &images.data.unwrap()[0];
-        let base64 = image.b64_json.clone();
-
+        let image = images
+            .data
+            .and_then(|data| data.into_iter().next())
+            .ok_or_else(|| LeoError::Decode("Image generation response contains no image".into()))?;
         // Create and return Image instance
-        Ok(Image::create_from_base64(base64))
+        Image::create_from_base64(&image.b64_json)
     }
 }
\ No newline at end of file
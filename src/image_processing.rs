@@ -0,0 +1,108 @@
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType as PngFilter, PngEncoder},
+        webp::WebPEncoder,
+    },
+    imageops::FilterType,
+    DynamicImage,
+};
+use crate::{
+    config::MediaTarget,
+    error::{LeoError, Result},
+    image::Image,
+    image_format::ImageFormat,
+};
+
+// Highest JPEG quality tried, higher settings grow the file without a visible gain
+const MAX_QUALITY: u8 = 92;
+// Factor applied to both sides while the image is too large even at the lowest quality
+const DOWNSCALE_STEP: f32 = 0.75;
+// Smallest side worth downscaling to before giving up on the budget
+const MIN_DIMENSION: u32 = 64;
+
+// Fit an image into the limits of a destination, returning it unchanged when it already fits.
+// Larger images are downscaled, then re-encoded with the highest quality within the byte budget
+pub fn fit(image: &Image, target: &MediaTarget) -> Result<Image> {
+    let bytes = image.bytes();
+    if image.width <= target.max_dimension && image.height <= target.max_dimension && bytes.len() <= target.max_bytes {
+        return Ok(image.clone());
+    }
+
+    let mut decoded = image::load_from_memory(bytes)?;
+    if decoded.width() > target.max_dimension || decoded.height() > target.max_dimension {
+        decoded = decoded.resize(target.max_dimension, target.max_dimension, FilterType::Lanczos3);
+    }
+
+    loop {
+        if let Some(encoded) = encode_within(&decoded, target)? {
+            return Image::create_from_bytes(encoded);
+        }
+
+        let width = (decoded.width() as f32 * DOWNSCALE_STEP) as u32;
+        let height = (decoded.height() as f32 * DOWNSCALE_STEP) as u32;
+        if width.min(height) < MIN_DIMENSION {
+            return Err(LeoError::Decode(format!(
+                "Image does not fit in {} bytes as {:?}",
+                target.max_bytes, target.format
+            )));
+        }
+        decoded = decoded.resize(width, height, FilterType::Lanczos3);
+    }
+}
+
+// Fit an image on the blocking thread pool, encoding large images takes too long for a worker
+pub async fn fit_off_thread(image: Image, target: MediaTarget) -> Result<Image> {
+    tokio::task::spawn_blocking(move || fit(&image, &target))
+        .await
+        .map_err(|err| LeoError::Decode(format!("Image processing failed {}", err)))?
+}
+
+// Encode in the target format, None when the result exceeds the budget
+fn encode_within(image: &DynamicImage, target: &MediaTarget) -> Result<Option<Vec<u8>>> {
+    let encoded = match target.format {
+        ImageFormat::Jpeg => return jpeg_within(image, target.max_bytes, target.min_quality),
+        ImageFormat::Png => {
+            let mut encoded = Vec::new();
+            image.write_with_encoder(PngEncoder::new_with_quality(
+                &mut encoded,
+                CompressionType::Best,
+                PngFilter::Adaptive,
+            ))?;
+            encoded
+        }
+        ImageFormat::Webp => {
+            let mut encoded = Vec::new();
+            image.write_with_encoder(WebPEncoder::new_lossless(&mut encoded))?;
+            encoded
+        }
+        ImageFormat::Gif => return Err(LeoError::Config("Images cannot be re-encoded as GIF".into())),
+    };
+
+    Ok((encoded.len() <= target.max_bytes).then_some(encoded))
+}
+
+// Binary search the highest JPEG quality within the budget
+fn jpeg_within(image: &DynamicImage, max_bytes: usize, min_quality: u8) -> Result<Option<Vec<u8>>> {
+    // JPEG has no alpha channel
+    let rgb = DynamicImage::ImageRgb8(image.to_rgb8());
+    let (mut low, mut high) = (min_quality, MAX_QUALITY.max(min_quality));
+    let mut best = None;
+
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let mut encoded = Vec::new();
+        rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, quality))?;
+
+        if encoded.len() <= max_bytes {
+            best = Some(encoded);
+            low = quality + 1;
+        } else if quality == min_quality {
+            break;
+        } else {
+            high = quality - 1;
+        }
+    }
+
+    Ok(best)
+}
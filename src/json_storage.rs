@@ -0,0 +1,400 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use crate::error::{LeoError, Result};
+use crate::storage::{StateLock, Storage, TweetRecord, TweetStatus};
+use crate::utils::now_secs;
+
+// Version of the state file written by this build
+//
+// 0: `{"file_path": ..., "items": [ids]}`, a bare set of processed IDs
+// 1: `{"records": {id: record}}`, per-tweet records without a version marker
+// 2: `{"version": 2, "records": {id: record}}`, records with output references
+// 3: `{"version": 3, "since_id": id, "records": {id: record}}`, search high-water mark
+pub const STATE_VERSION: u64 = 3;
+
+// Contents of the JSON state file
+#[derive(Default, Serialize, Deserialize)]
+struct StateFile {
+    // Schema version of the file
+    version: u64,
+    // Newest recorded mention
+    since_id: Option<String>,
+    // Processing records by tweet ID
+    records: HashMap<String, TweetRecord>,
+}
+
+// Storage keeping every record in memory and persisting them to a single JSON file
+pub struct JsonStorage {
+    // Path to storage file
+    file_path: String,
+    // Records loaded from the file
+    state: StateFile,
+    // Lock held while the storage is open
+    _lock: StateLock,
+}
+
+impl JsonStorage {
+    // Load storage from file, start empty if the file doesn't exist yet. Older schema versions
+    // are migrated after backing up the original. A file that cannot be parsed is refused,
+    // unless `recover` is set, in which case it is moved aside
+    pub fn read_from_file(file_path: &str, recover: bool) -> Result<Self> {
+        let lock = StateLock::acquire(file_path)?;
+        let storage = Self {
+            file_path: file_path.to_string(),
+            state: read_state(file_path, recover)?,
+            _lock: lock,
+        };
+
+        Ok(storage)
+    }
+
+    // Save current storage state to a temporary file and atomically replace the old one
+    pub fn write_to_file(&self) -> Result<()> {
+        let tmp_path = format!("{}.tmp", self.file_path);
+
+        // Serialize to the temporary file and make sure it reached the disk
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &self.state).map_err(|err| LeoError::Storage(err.to_string()))?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        // Readers see either the old or the new file, never a partial one
+        fs::rename(&tmp_path, &self.file_path)?;
+        sync_parent_dir(&self.file_path);
+
+        Ok(())
+    }
+}
+
+// Parse the state file, migrating old versions and refusing or moving aside a corrupt one
+fn read_state(file_path: &str, recover: bool) -> Result<StateFile> {
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(StateFile::new()),
+        Err(err) => return Err(LeoError::Storage(format!("Unable to open {} {}", file_path, err))),
+    };
+
+    // Earlier versions created an empty file before the first write
+    if file.metadata()?.len() == 0 {
+        return Ok(StateFile::new());
+    }
+
+    let parsed = serde_json::from_reader(BufReader::new(file))
+        .map_err(|err| err.to_string())
+        .and_then(|value| Ok((detect_version(&value)?, value)));
+
+    // Written by a newer build, moving it aside would lose its history
+    if let Ok((version, _)) = &parsed {
+        if *version > STATE_VERSION {
+            return Err(LeoError::Storage(format!(
+                "{} has version {}, newer than supported version {}",
+                file_path, version, STATE_VERSION
+            )));
+        }
+    }
+
+    let parsed = parsed.and_then(|(version, value)| migrate(file_path, value, version));
+    match parsed {
+        Ok(state) => Ok(state),
+        Err(err) if recover => {
+            let corrupt_path = format!("{}.corrupt-{}", file_path, now_secs());
+            fs::rename(file_path, &corrupt_path)?;
+            println!("{} is corrupt ({}), moved it to {} and starting empty", file_path, err, corrupt_path);
+            Ok(StateFile::new())
+        }
+        Err(err) => Err(LeoError::Storage(format!(
+            "{} is corrupt ({}). Set bot.recover_state (LEO_RECOVER_STATE=true) to move it aside and start empty",
+            file_path, err
+        ))),
+    }
+}
+
+impl StateFile {
+    // Create an empty state of the current version
+    fn new() -> Self {
+        Self {
+            version: STATE_VERSION,
+            since_id: None,
+            records: HashMap::new(),
+        }
+    }
+}
+
+// Upgrade a parsed state file to the current version, backing up the original first
+fn migrate(file_path: &str, mut value: Value, mut version: u64) -> std::result::Result<StateFile, String> {
+    if version < STATE_VERSION {
+        let backup_path = format!("{}.v{}.bak", file_path, version);
+        fs::copy(file_path, &backup_path).map_err(|err| format!("unable to back up to {} {}", backup_path, err))?;
+        println!("Migrating {} from version {} to {}, original kept as {}", file_path, version, STATE_VERSION, backup_path);
+    }
+
+    while version < STATE_VERSION {
+        value = match version {
+            0 => migrate_v0(value)?,
+            1 => migrate_v1(value),
+            2 => migrate_v2(value),
+            _ => unreachable!("every version below STATE_VERSION has a migration"),
+        };
+        version += 1;
+    }
+
+    let mut state: StateFile = serde_json::from_value(value).map_err(|err| err.to_string())?;
+
+    // Records are keyed by ID, make sure the copy inside the record agrees
+    for (id, record) in state.records.iter_mut() {
+        record.id = id.clone();
+    }
+
+    Ok(state)
+}
+
+// Find the schema version of a state file, unversioned files are told apart by their fields
+fn detect_version(value: &Value) -> std::result::Result<u64, String> {
+    match value.get("version") {
+        Some(version) => version.as_u64().ok_or_else(|| format!("invalid version {}", version)),
+        None if value.get("items").is_some() => Ok(0),
+        None if value.get("records").is_some() => Ok(1),
+        None => Err("unknown state file layout".into()),
+    }
+}
+
+// Version 0 to 1: turn each processed ID into a done record
+fn migrate_v0(value: Value) -> std::result::Result<Value, String> {
+    let items = value["items"].as_array().ok_or("items is not a list")?;
+    let now = now_secs();
+
+    let mut records = Map::new();
+    for id in items {
+        let id = id.as_str().ok_or("items contains a non-string ID")?;
+        records.insert(
+            id.to_string(),
+            json!({
+                "id": id,
+                "tweet": { "id": id },
+                "status": TweetStatus::Done.as_str(),
+                "attempts": 0,
+                "last_error": null,
+                "created_at": now,
+                "updated_at": now,
+            }),
+        );
+    }
+
+    Ok(json!({ "records": records }))
+}
+
+// Version 1 to 2: add the version marker and output references
+fn migrate_v1(mut value: Value) -> Value {
+    if let Some(records) = value["records"].as_object_mut() {
+        for record in records.values_mut() {
+            if record.get("output").is_none() {
+                record["output"] = json!({ "image_path": null, "reply_id": null });
+            }
+        }
+    }
+    value["version"] = json!(2);
+    value
+}
+
+// Version 2 to 3: start without a high-water mark, the first search after the upgrade sets it
+fn migrate_v2(mut value: Value) -> Value {
+    value["since_id"] = Value::Null;
+    value["version"] = json!(3);
+    value
+}
+
+// Persist the rename itself, best effort since not every platform can sync directories
+fn sync_parent_dir(file_path: &str) {
+    let parent = match Path::new(file_path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(parent) {
+        dir.sync_all().ok();
+    }
+}
+
+impl Storage for JsonStorage {
+    fn get(&self, tweet: &str) -> Result<Option<TweetRecord>> {
+        Ok(self.state.records.get(tweet).cloned())
+    }
+
+    fn put(&mut self, record: TweetRecord) -> Result<()> {
+        self.state.records.insert(record.id.clone(), record);
+        Ok(())
+    }
+
+    fn remove(&mut self, tweet: &str) -> Result<bool> {
+        Ok(self.state.records.remove(tweet).is_some())
+    }
+
+    fn with_status(&self, status: TweetStatus) -> Result<Vec<TweetRecord>> {
+        let mut records: Vec<TweetRecord> = self
+            .state
+            .records
+            .values()
+            .filter(|record| record.status == status)
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| record.created_at);
+        Ok(records)
+    }
+
+    fn since_id(&self) -> Result<Option<String>> {
+        Ok(self.state.since_id.clone())
+    }
+
+    fn set_since_id(&mut self, since_id: &str) -> Result<()> {
+        self.state.since_id = Some(since_id.to_string());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_to_file()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        // The file is rewritten whole, only the in-memory map holds on to freed space
+        self.state.records.shrink_to_fit();
+        self.write_to_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    // State file path in a fresh directory, removed when the guard is dropped
+    struct TempState {
+        dir: std::path::PathBuf,
+        path: String,
+    }
+
+    impl TempState {
+        fn with_contents(contents: &Value) -> Self {
+            let dir = std::env::temp_dir().join(format!("leo-json-storage-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("state.json").to_string_lossy().into_owned();
+            fs::write(&path, contents.to_string()).unwrap();
+            Self { dir, path }
+        }
+
+        fn saved(&self) -> Value {
+            serde_json::from_str(&fs::read_to_string(&self.path).unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for TempState {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    fn record_v1(id: &str, status: &str) -> Value {
+        json!({
+            "id": id,
+            "tweet": { "id": id, "username": "someone" },
+            "status": status,
+            "attempts": 1,
+            "last_error": "timeout",
+            "created_at": 100,
+            "updated_at": 200,
+        })
+    }
+
+    #[test]
+    fn migrates_baseline_items() {
+        let state = TempState::with_contents(&json!({ "file_path": "storage.json", "items": ["1", "2"] }));
+
+        let storage = JsonStorage::read_from_file(&state.path, false).unwrap();
+        assert_eq!(storage.state.version, STATE_VERSION);
+        assert_eq!(storage.state.since_id, None);
+        assert_eq!(storage.state.records.len(), 2);
+        let record = storage.get("2").unwrap().unwrap();
+        assert_eq!(record.id, "2");
+        assert_eq!(record.tweet.id.as_deref(), Some("2"));
+        assert_eq!(record.status, TweetStatus::Done);
+        assert_eq!(record.attempts, 0);
+        assert_eq!(record.output, Default::default());
+
+        // The original is backed up and the next write uses the current layout
+        assert!(Path::new(&format!("{}.v0.bak", state.path)).is_file());
+        storage.write_to_file().unwrap();
+        let saved = state.saved();
+        assert_eq!(saved["version"], json!(STATE_VERSION));
+        assert!(saved.get("items").is_none());
+    }
+
+    #[test]
+    fn migrates_v1_records() {
+        let state = TempState::with_contents(&json!({
+            "records": { "1": record_v1("1", "done"), "2": record_v1("2", "pending") },
+        }));
+
+        let storage = JsonStorage::read_from_file(&state.path, false).unwrap();
+        assert_eq!(storage.state.version, STATE_VERSION);
+        assert_eq!(storage.state.since_id, None);
+        let record = storage.get("2").unwrap().unwrap();
+        assert_eq!(record.status, TweetStatus::Pending);
+        assert_eq!(record.attempts, 1);
+        assert_eq!(record.last_error.as_deref(), Some("timeout"));
+        assert_eq!(record.created_at, 100);
+        assert_eq!(record.output, Default::default());
+        assert!(Path::new(&format!("{}.v1.bak", state.path)).is_file());
+    }
+
+    #[test]
+    fn migrates_v2_records() {
+        let mut record = record_v1("1", "done");
+        record["output"] = json!({ "image_path": "/tmp/image.png", "reply_id": "9" });
+        let state = TempState::with_contents(&json!({ "version": 2, "records": { "1": record } }));
+
+        let storage = JsonStorage::read_from_file(&state.path, false).unwrap();
+        assert_eq!(storage.state.version, STATE_VERSION);
+        assert_eq!(storage.state.since_id, None);
+        let output = storage.get("1").unwrap().unwrap().output;
+        assert_eq!(output.image_path.as_deref(), Some("/tmp/image.png"));
+        assert_eq!(output.reply_id.as_deref(), Some("9"));
+        assert!(Path::new(&format!("{}.v2.bak", state.path)).is_file());
+    }
+
+    #[test]
+    fn keeps_current_version() {
+        let state = TempState::with_contents(&json!({
+            "version": STATE_VERSION,
+            "since_id": "5",
+            "records": { "1": record_v1("1", "failed") },
+        }));
+
+        let storage = JsonStorage::read_from_file(&state.path, false).unwrap();
+        assert_eq!(storage.since_id().unwrap().as_deref(), Some("5"));
+        assert_eq!(storage.get("1").unwrap().unwrap().status, TweetStatus::Failed);
+        assert!(!Path::new(&format!("{}.v{}.bak", state.path, STATE_VERSION)).exists());
+    }
+
+    #[test]
+    fn refuses_newer_version() {
+        let contents = json!({ "version": STATE_VERSION + 1, "records": {} });
+        let state = TempState::with_contents(&contents);
+
+        // Not even recovery may move aside the history of a newer build
+        let err = JsonStorage::read_from_file(&state.path, true).err().unwrap();
+        assert!(matches!(err, LeoError::Storage(message) if message.contains("newer than supported")));
+        assert_eq!(state.saved(), contents);
+    }
+
+    #[test]
+    fn refuses_unknown_layout() {
+        let contents = json!({ "tweets": ["1"] });
+        let state = TempState::with_contents(&contents);
+
+        let err = JsonStorage::read_from_file(&state.path, false).err().unwrap();
+        assert!(matches!(err, LeoError::Storage(message) if message.contains("unknown state file layout")));
+        assert_eq!(state.saved(), contents);
+    }
+}
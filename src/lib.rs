@@ -1,8 +1,19 @@
+pub mod config;
+pub mod downloader;
+pub mod error;
 pub mod http_client;
 pub mod image;
+pub mod image_format;
 pub mod image_gen;
+pub mod image_processing;
+pub mod json_storage;
+pub mod memory_storage;
+pub mod pipeline;
+pub mod prompt_gen;
+pub mod schedule;
 pub mod utils;
 pub mod vision;
 pub mod twitter;
 pub mod handler;
+pub mod sqlite_storage;
 pub mod storage;
\ No newline at end of file
@@ -1,31 +1,221 @@
-use std::time::Duration;
-use leo::{handler::Handler, storage::Storage};
-use tokio::time::sleep;
+use std::{collections::HashSet, env, future::Future, io, pin::Pin, time::Duration};
+use anyhow::bail;
+use leo::{
+    config::{Config, ConfigScope},
+    error::Result,
+    handler::{Handler, IterationReport},
+    schedule::PollSchedule,
+    storage::{self, Storage},
+    utils::{format_date, parse_date, retention_cutoff},
+};
+use tokio::{
+    signal,
+    time::{sleep, timeout},
+};
 
-// File path for persistent storage
-const STORAGE_FILE: &'static str = "tweets.json";
+// Outcome of an iteration raced against a shutdown signal
+enum Run {
+    // Iteration completed before any signal
+    Finished(Result<IterationReport>),
+    // Shutdown was requested, in-flight tweets were given the grace period
+    Interrupted,
+}
 
 // Main async function using tokio runtime
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Load environment variables from .env file
     dotenv::dotenv().ok();
-    // Initialize the environment logger
-    env_logger::init();
+    // Initialize the environment logger, showing the bot's own warnings unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("leo=info")).init();
 
-    // Load processed tweets from storage file
-    let storage = Storage::read_from_file(STORAGE_FILE)?;
+    // Maintenance commands only need the storage settings
+    let args: Vec<String> = env::args().skip(1).collect();
+    let scope = match args.first().map(String::as_str) {
+        None | Some("backfill") => ConfigScope::Bot,
+        Some(_) => ConfigScope::Storage,
+    };
 
-    // Create a new instance of Handler with storage
-    let mut handler = Handler::initialize(storage).await?;
+    // Load and validate configuration
+    let config = Config::load(scope)?;
 
-    // Infinite loop to continuously process tweets
+    // Load processed tweets from the configured storage
+    let mut storage = storage::open(&config.bot)?;
+
+    // Time in-flight tweets get to finish once a shutdown signal arrives
+    let grace = Duration::from_secs(config.bot.shutdown_grace_secs);
+
+    // Run a maintenance command instead of the bot when one is given
+    if args.first().map(String::as_str) == Some("backfill") {
+        let [from, to] = &args[1..] else {
+            bail!("Usage: leo backfill <from YYYY-MM-DD> <to YYYY-MM-DD>");
+        };
+        // Both days are included
+        let (from, until) = (parse_date(from)?, parse_date(to)? + 24 * 60 * 60);
+        if from >= until {
+            bail!("Backfill range is empty, the first day is after the last one");
+        }
+        // Records of answered mentions are pruned after the retention window, older mentions
+        // would be answered a second time
+        if config.bot.retention_days > 0 {
+            let earliest = retention_cutoff(config.bot.retention_days).div_ceil(24 * 60 * 60) * 24 * 60 * 60;
+            if from < earliest {
+                bail!(
+                    "Backfill cannot start before {}, older answered mentions were pruned after bot.retention_days",
+                    format_date(earliest)
+                );
+            }
+        }
+
+        let handler = Handler::initialize(&config, storage).await?;
+        let mut shutdown = Box::pin(shutdown_signal());
+        match run_until_shutdown(&handler, handler.backfill(from, until), &mut shutdown, grace).await? {
+            Run::Finished(report) => println!("Backfill finished: {}", report?),
+            Run::Interrupted => println!("Backfill interrupted, run it again to continue"),
+        }
+        handler.flush()?;
+        return Ok(());
+    }
+    if let Some(command) = args.first() {
+        return run_command(storage.as_mut(), &config, command, &args[1..]);
+    }
+
+    // Create a new instance of Handler with configuration and storage
+    let handler = Handler::initialize(&config, storage).await?;
+
+    // Loop continuously processing tweets until a shutdown signal or a fatal error
+    let mut schedule = PollSchedule::from_config(&config.bot);
+    let mut shutdown = Box::pin(shutdown_signal());
     loop {
         // Print status message for each iteration
         println!("Starting a new iteration...");
-        // Process tweets using the handler
-        handler.handle_tweets().await?;
-        // Sleep for 2 minutes before next iteration
-        sleep(Duration::from_secs(1 * 60)).await;
+        // Process tweets using the handler and adapt the delay to the outcome
+        let delay = match run_until_shutdown(&handler, handler.handle_tweets(), &mut shutdown, grace).await? {
+            Run::Interrupted => break,
+            Run::Finished(Ok(report)) => {
+                println!("Iteration finished: {}", report);
+                schedule.on_success(&report)
+            }
+            Run::Finished(Err(err)) if err.is_fatal() => return Err(err.into()),
+            Run::Finished(Err(err)) => {
+                println!("Iteration failed: {}", err);
+                schedule.on_error(&err)
+            }
+        };
+        // Sleep before next iteration unless shutdown is requested meanwhile
+        println!("Next iteration in {}s", delay.as_secs());
+        tokio::select! {
+            _ = sleep(delay) => {}
+            signal = shutdown.as_mut() => {
+                signal?;
+                break;
+            }
+        }
+    }
+
+    handler.flush()?;
+    println!("Shut down cleanly");
+    Ok(())
+}
+
+// Wait for SIGINT, or SIGTERM on Unix
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
+
+// Run an iteration to completion. On a shutdown signal stop starting tweets and give the ones in
+// flight the grace period, a second interrupt abandons them. Abandoned tweets stay in progress in
+// the storage and are retried on the next start
+async fn run_until_shutdown<S>(
+    handler: &Handler,
+    iteration: impl Future<Output = Result<IterationReport>>,
+    shutdown: &mut Pin<Box<S>>,
+    grace: Duration,
+) -> anyhow::Result<Run>
+where
+    S: Future<Output = io::Result<()>>,
+{
+    tokio::pin!(iteration);
+    tokio::select! {
+        result = &mut iteration => Ok(Run::Finished(result)),
+        signal = shutdown.as_mut() => {
+            signal?;
+            println!("Shutdown requested, waiting up to {}s for in-flight tweets", grace.as_secs());
+            handler.stop();
+            tokio::select! {
+                result = timeout(grace, &mut iteration) => match result {
+                    Ok(Ok(report)) => println!("In-flight tweets finished: {}", report),
+                    Ok(Err(err)) => println!("In-flight tweets finished with an error: {}", err),
+                    Err(_) => println!("In-flight tweets did not finish in time, they are retried on the next start"),
+                },
+                _ = signal::ctrl_c() => println!("Interrupted again, in-flight tweets are retried on the next start"),
+            }
+            Ok(Run::Interrupted)
+        }
     }
+}
+
+// Inspect or requeue dead letters, or prune expired records
+fn run_command(storage: &mut dyn Storage, config: &Config, command: &str, args: &[String]) -> anyhow::Result<()> {
+    match command {
+        "dead-letters" => {
+            for record in storage.dead_letters()? {
+                println!(
+                    "{}\t@{}\tattempts={}\t{}",
+                    record.tweet.id.as_deref().unwrap_or_default(),
+                    record.tweet.username.as_deref().unwrap_or_default(),
+                    record.attempts,
+                    record.last_error.as_deref().unwrap_or_default()
+                );
+            }
+        }
+        "requeue" => {
+            if args.is_empty() {
+                bail!("Usage: leo requeue <tweet id>...");
+            }
+            for id in args {
+                if storage.requeue(id)? {
+                    println!("Requeued {}", id);
+                } else {
+                    println!("{} is not a dead letter", id);
+                }
+            }
+            storage.flush()?;
+        }
+        "prune" => {
+            // An explicit day count overrides the configured retention
+            let days = match args.first() {
+                Some(days) => days.parse()?,
+                None => config.bot.retention_days,
+            };
+            if days == 0 {
+                bail!("Retention is disabled, pass a number of days: leo prune <days>");
+            }
+
+            // Without a search there is no horizon, every expired record is removed
+            let report = storage.prune(retention_cutoff(days), &HashSet::new())?;
+            storage.compact()?;
+            for record in &report.removed {
+                println!(
+                    "{}\t{}\tupdated_at={}",
+                    record.id,
+                    record.status.as_str(),
+                    record.updated_at
+                );
+            }
+            println!("{}", report);
+        }
+        _ => bail!("Unknown command {}, expected dead-letters, requeue, prune or backfill", command),
+    }
+
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use crate::error::Result;
+use crate::storage::{Storage, TweetRecord, TweetStatus};
+
+// Storage keeping records in memory only, for tests and dry runs
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    // Processing records by tweet ID
+    records: HashMap<String, TweetRecord>,
+    // Newest recorded mention
+    since_id: Option<String>,
+}
+
+impl MemoryStorage {
+    // Create an empty storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, tweet: &str) -> Result<Option<TweetRecord>> {
+        Ok(self.records.get(tweet).cloned())
+    }
+
+    fn put(&mut self, record: TweetRecord) -> Result<()> {
+        self.records.insert(record.id.clone(), record);
+        Ok(())
+    }
+
+    fn remove(&mut self, tweet: &str) -> Result<bool> {
+        Ok(self.records.remove(tweet).is_some())
+    }
+
+    fn with_status(&self, status: TweetStatus) -> Result<Vec<TweetRecord>> {
+        let mut records: Vec<TweetRecord> = self
+            .records
+            .values()
+            .filter(|record| record.status == status)
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| record.created_at);
+        Ok(records)
+    }
+
+    fn since_id(&self) -> Result<Option<String>> {
+        Ok(self.since_id.clone())
+    }
+
+    fn set_since_id(&mut self, since_id: &str) -> Result<()> {
+        self.since_id = Some(since_id.to_string());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::{
+    config::Config,
+    error::{LeoError, Result},
+    image::{Image, ImageGenerator},
+    twitter::{ExtractedTweet, PostedTweet, TweetPage, UserProfile},
+};
+
+// Stage finding mentions of the bot and looking up their authors
+#[async_trait]
+pub trait MentionSource: Send + Sync {
+    // Username the bot runs as, mentions are searched for it
+    fn username(&self) -> &str;
+
+    // Search for a page of tweets matching a query, continuing from the cursor of the previous page
+    async fn search(&self, query: &str, max_tweets: i32, cursor: Option<String>) -> Result<TweetPage>;
+
+    // Look up the profile of a user
+    async fn lookup_profile(&self, username: &str) -> Result<UserProfile>;
+}
+
+// Stage downloading avatars and banners
+#[async_trait]
+pub trait ImageFetcher: Send + Sync {
+    // Download and decode the image at the URL
+    async fn fetch_image(&self, url: &str) -> Result<Image>;
+}
+
+// Stage turning an avatar into descriptive labels
+#[async_trait]
+pub trait AvatarDescriber: Send + Sync {
+    // Describe the avatar, most relevant label first
+    async fn describe(&self, image: Image) -> Result<Vec<String>>;
+}
+
+// Stage turning avatar labels into an image generation prompt
+#[async_trait]
+pub trait PromptWriter: Send + Sync {
+    // Write a prompt from the comma separated labels
+    async fn write_prompt(&self, description: &str) -> Result<String>;
+}
+
+// Stage delivering the generated artwork to the requester
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    // Publish the artwork in response to a tweet
+    async fn publish(&self, tweet: &ExtractedTweet, text: &str, image: &Image) -> Result<PostedTweet>;
+}
+
+// Set of stage implementations used by the handler
+#[derive(Clone)]
+pub struct Stages {
+    // Mention search and profile lookup stage
+    pub mentions: Arc<dyn MentionSource>,
+    // Avatar and banner download stage
+    pub image_fetcher: Arc<dyn ImageFetcher>,
+    // Avatar description stage
+    pub describer: Arc<dyn AvatarDescriber>,
+    // Prompt writing stage
+    pub prompt_writer: Arc<dyn PromptWriter>,
+    // Image generation stage
+    pub image_generator: Arc<dyn ImageGenerator>,
+    // Publishing stage
+    pub publisher: Arc<dyn Publisher>,
+}
+
+// Per-provider concurrency limits shared by all workers
+pub struct Limits {
+    // Twitter scraper calls
+    pub twitter: Semaphore,
+    // Google Vision requests
+    pub vision: Semaphore,
+    // OpenAI completion requests
+    pub completions: Semaphore,
+    // OpenAI image generation requests
+    pub images: Semaphore,
+}
+
+impl Limits {
+    // Create limits from the configured maximums
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            twitter: Semaphore::new(config.twitter.max_concurrent),
+            vision: Semaphore::new(config.vision.max_concurrent),
+            completions: Semaphore::new(config.openai.max_concurrent_completions),
+            images: Semaphore::new(config.openai.max_concurrent_images),
+        }
+    }
+}
+
+// Wait for a free slot of a provider
+pub async fn acquire(semaphore: &Semaphore) -> Result<SemaphorePermit<'_>> {
+    semaphore
+        .acquire()
+        .await
+        .map_err(|err| LeoError::Config(format!("Concurrency limit closed {}", err)))
+}
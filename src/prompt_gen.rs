@@ -0,0 +1,73 @@
+use async_trait::async_trait;
+use rig::completion::{CompletionError, Prompt, PromptError};
+use rig::providers::openai;
+
+use crate::{
+    config::{OpenAiConfig, UpstreamConfig},
+    error::{ApiError, LeoError, Result},
+    http_client::with_retry,
+    pipeline::PromptWriter,
+};
+
+// Endpoint called by the rig client, used in error messages
+const COMPLETIONS_URL: &str = "https://api.openai.com/v1/chat/completions";
+
+// Prompt writer backed by an OpenAI completion model
+pub struct PromptGen {
+    // OpenAI client instance
+    client: openai::Client,
+    // Timeout and retry settings, the rig client has none of its own
+    upstream: UpstreamConfig,
+    // Completion model name
+    model: String,
+    // Prompt template, `{}` is replaced by the description
+    template: String,
+}
+
+impl PromptGen {
+    // Initialize new prompt writer with a prompt template
+    pub fn initialize(config: &OpenAiConfig, upstream: &UpstreamConfig, template: &str) -> Result<Self> {
+        Ok(Self {
+            client: openai::Client::new(&config.api_key),
+            upstream: *upstream,
+            model: config.completion_model.clone(),
+            template: template.to_string(),
+        })
+    }
+}
+
+// Implementation of PromptWriter trait for GPT models
+#[async_trait]
+impl PromptWriter for PromptGen {
+    // Translate and optimize description using the completion model
+    async fn write_prompt(&self, description: &str) -> Result<String> {
+        let agent = self.client.agent(&self.model).build();
+        let prompt_string = self.template.replace("{}", description);
+        let response: String = with_retry(&self.upstream, || async {
+            agent.prompt(&prompt_string).await.map_err(classify)
+        })
+        .await?;
+
+        Ok(response)
+    }
+}
+
+// Map a rig error to the error class, only transport failures and rate limits are retried
+fn classify(err: PromptError) -> LeoError {
+    match err {
+        PromptError::CompletionError(CompletionError::HttpError(err)) => err.into(),
+        // rig keeps only the body of error responses, OpenAI names the cause in its code
+        PromptError::CompletionError(CompletionError::ProviderError(body)) => {
+            let api = ApiError::from_body(COMPLETIONS_URL, 0, &body);
+            match api.code.as_deref() {
+                Some("insufficient_quota") | Some("billing_hard_limit_reached") => {
+                    LeoError::Quota(ApiError { status: 429, ..api })
+                }
+                Some("rate_limit_exceeded") => LeoError::RateLimited(api.message),
+                Some("invalid_api_key") => LeoError::Auth(api.message),
+                _ => LeoError::Http(format!("{} failed: {}", COMPLETIONS_URL, api.message)),
+            }
+        }
+        err => LeoError::Decode(err.to_string()),
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+use crate::config::BotConfig;
+use crate::error::LeoError;
+use crate::handler::IterationReport;
+
+// Delay between polling iterations, shortened while mentions arrive and growing after failures
+pub struct PollSchedule {
+    // Delay while mentions keep arriving
+    busy: Duration,
+    // Delay while no mentions arrive
+    idle: Duration,
+    // Upper bound of the delay after failures
+    max_backoff: Duration,
+    // Delay after the last successful iteration
+    current: Duration,
+    // Consecutive failed iterations
+    failures: u32,
+}
+
+impl PollSchedule {
+    // Create a schedule starting at the idle interval
+    pub fn from_config(config: &BotConfig) -> Self {
+        let idle = Duration::from_secs(config.poll_interval_secs);
+        Self {
+            busy: Duration::from_secs(config.min_poll_interval_secs),
+            idle,
+            max_backoff: Duration::from_secs(config.max_poll_backoff_secs),
+            current: idle,
+            failures: 0,
+        }
+    }
+
+    // Delay after a successful iteration, poll again soon when mentions arrived and slow down
+    // gradually once they stop
+    pub fn on_success(&mut self, report: &IterationReport) -> Duration {
+        self.failures = 0;
+        self.current = if report.found > 0 {
+            self.busy
+        } else {
+            (self.current * 2).min(self.idle)
+        };
+        self.current
+    }
+
+    // Delay after a failed iteration, doubled on every consecutive failure. Rate limits start one
+    // step further since polling again at the usual pace would hit the limit again
+    pub fn on_error(&mut self, err: &LeoError) -> Duration {
+        self.failures += 1;
+        let doublings = if err.is_rate_limited() { self.failures } else { self.failures - 1 };
+        self.current = self.idle;
+        self.idle.saturating_mul(1 << doublings.min(16)).min(self.max_backoff)
+    }
+}
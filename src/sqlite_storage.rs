@@ -0,0 +1,200 @@
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use serde::de::DeserializeOwned;
+use crate::error::{LeoError, Result};
+use crate::storage::{StateLock, Storage, TweetOutput, TweetRecord, TweetStatus};
+
+// Columns selected for a record, in the order read by `read_record`
+const RECORD_COLUMNS: &str =
+    "id, tweet, status, attempts, last_error, created_at, updated_at, image_path, reply_id, labels, prompt";
+
+// Schema migrations, the database `user_version` counts how many were applied
+const MIGRATIONS: &[&str] = &[
+    // 1: processing records
+    "CREATE TABLE IF NOT EXISTS tweets (
+        id TEXT PRIMARY KEY,
+        tweet TEXT NOT NULL,
+        status TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        last_error TEXT,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS tweets_status_created_at ON tweets (status, created_at);",
+    // 2: output references
+    "ALTER TABLE tweets ADD COLUMN image_path TEXT;
+    ALTER TABLE tweets ADD COLUMN reply_id TEXT;",
+    // 3: named values such as the search high-water mark
+    "CREATE TABLE settings (
+        name TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );",
+    // 4: stage checkpoints, labels are stored as a JSON list
+    "ALTER TABLE tweets ADD COLUMN labels TEXT;
+    ALTER TABLE tweets ADD COLUMN prompt TEXT;",
+];
+
+// Setting holding the newest recorded mention
+const SINCE_ID: &str = "since_id";
+
+// Storage persisting records in an embedded SQLite database
+pub struct SqliteStorage {
+    // Open database connection
+    connection: Connection,
+    // Lock held while the storage is open
+    _lock: StateLock,
+}
+
+impl SqliteStorage {
+    // Open or create the database and bring its schema to the current version
+    pub fn open(file_path: &str) -> Result<Self> {
+        let lock = StateLock::acquire(file_path)?;
+        let mut connection = Connection::open(file_path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut connection, file_path)?;
+
+        Ok(Self {
+            connection,
+            _lock: lock,
+        })
+    }
+}
+
+// Apply every migration newer than the `user_version` of the database, each in its own transaction
+fn migrate(connection: &mut Connection, file_path: &str) -> Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(LeoError::Storage(format!(
+            "{} has schema version {}, newer than supported version {}",
+            file_path,
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+        if version > 0 {
+            println!("Migrated {} to schema version {}", file_path, index + 1);
+        }
+    }
+
+    Ok(())
+}
+
+// Convert a selected row into a record
+fn read_record(row: &Row) -> rusqlite::Result<(TweetRecord, String)> {
+    let status: String = row.get(2)?;
+    let labels: Option<String> = row.get(9)?;
+    let record = TweetRecord {
+        id: row.get(0)?,
+        tweet: json_column(1, &row.get::<_, String>(1)?)?,
+        status: TweetStatus::Pending,
+        attempts: row.get(3)?,
+        last_error: row.get(4)?,
+        created_at: row.get::<_, i64>(5)? as u64,
+        updated_at: row.get::<_, i64>(6)? as u64,
+        output: TweetOutput {
+            labels: labels.map(|labels| json_column(9, &labels)).transpose()?,
+            prompt: row.get(10)?,
+            image_path: row.get(7)?,
+            reply_id: row.get(8)?,
+        },
+    };
+
+    Ok((record, status))
+}
+
+// Parse a JSON column, refusing a corrupt value instead of resetting it
+fn json_column<T: DeserializeOwned>(index: usize, value: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(value)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(err)))
+}
+
+// Resolve the status read next to a record
+fn with_parsed_status((mut record, status): (TweetRecord, String)) -> Result<TweetRecord> {
+    record.status = TweetStatus::parse(&status)?;
+    Ok(record)
+}
+
+impl Storage for SqliteStorage {
+    fn get(&self, tweet: &str) -> Result<Option<TweetRecord>> {
+        let row = self
+            .connection
+            .query_row(
+                &format!("SELECT {} FROM tweets WHERE id = ?1", RECORD_COLUMNS),
+                params![tweet],
+                read_record,
+            )
+            .optional()?;
+
+        row.map(with_parsed_status).transpose()
+    }
+
+    fn put(&mut self, record: TweetRecord) -> Result<()> {
+        self.connection.execute(
+            &format!(
+                "INSERT OR REPLACE INTO tweets ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                RECORD_COLUMNS
+            ),
+            params![
+                record.id,
+                serde_json::to_string(&record.tweet)?,
+                record.status.as_str(),
+                record.attempts,
+                record.last_error,
+                record.created_at as i64,
+                record.updated_at as i64,
+                record.output.image_path,
+                record.output.reply_id,
+                record.output.labels.as_ref().map(serde_json::to_string).transpose()?,
+                record.output.prompt,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove(&mut self, tweet: &str) -> Result<bool> {
+        let removed = self.connection.execute("DELETE FROM tweets WHERE id = ?1", params![tweet])?;
+        Ok(removed > 0)
+    }
+
+    fn with_status(&self, status: TweetStatus) -> Result<Vec<TweetRecord>> {
+        let mut statement = self.connection.prepare_cached(&format!(
+            "SELECT {} FROM tweets WHERE status = ?1 ORDER BY created_at",
+            RECORD_COLUMNS
+        ))?;
+        let rows = statement.query_map(params![status.as_str()], read_record)?;
+
+        rows.map(|row| with_parsed_status(row?)).collect()
+    }
+
+    fn since_id(&self) -> Result<Option<String>> {
+        let since_id = self
+            .connection
+            .query_row("SELECT value FROM settings WHERE name = ?1", params![SINCE_ID], |row| row.get(0))
+            .optional()?;
+        Ok(since_id)
+    }
+
+    fn set_since_id(&mut self, since_id: &str) -> Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO settings (name, value) VALUES (?1, ?2)",
+            params![SINCE_ID, since_id],
+        )?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Every statement is committed as it runs
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        // Fold the write-ahead log back into the database before rebuilding it
+        self.connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE); VACUUM;")?;
+        Ok(())
+    }
+}
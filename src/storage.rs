@@ -1,63 +1,522 @@
-use serde::{Deserialize, Serialize};
-use serde_json::{self, Error};
 use std::collections::HashSet;
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, BufWriter};
-
-// Structure for persistent storage of processed items
-#[derive(Serialize, Deserialize)]
-pub struct Storage {
-    // Path to storage file
-    file_path: String,
-    // Set of stored items (tweet IDs)
-    items: HashSet<String>,
-}
-
-impl Storage {
-    // Load storage from file, create new if file doesn't exist
-    pub fn read_from_file(file_path: &str) -> Result<Self, Error> {
-        // Open existing file or create new one
-        let file = File::open(file_path).unwrap_or_else(|_| File::create(file_path).unwrap());
-        // Create buffered reader for efficient reading
-        let reader = BufReader::new(file);
-        // Try to deserialize existing data or create empty storage
-        serde_json::from_reader(reader).or_else(|_| {
-            Ok(Storage {
-                file_path: file_path.to_string(),
-                items: HashSet::new(),
-            })
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::process;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use crate::config::BotConfig;
+use crate::error::{LeoError, Result};
+use crate::json_storage::JsonStorage;
+use crate::sqlite_storage::SqliteStorage;
+use crate::twitter::ExtractedTweet;
+use crate::utils::now_secs;
+
+// Processing status of a tweet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TweetStatus {
+    // Waiting to be processed or retried
+    Pending,
+    // Picked up by a worker
+    InProgress,
+    // Artwork was posted
+    Done,
+    // Gave up after too many attempts or a permanent error
+    Failed,
+    // Nothing to do for this tweet
+    Skipped,
+}
+
+// Processing record of a single tweet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TweetRecord {
+    // Tweet ID the record belongs to
+    #[serde(default)]
+    pub id: String,
+    // Tweet the record belongs to, kept so retries do not depend on the search window
+    pub tweet: ExtractedTweet,
+    // Current processing status
+    pub status: TweetStatus,
+    // Number of failed attempts
+    pub attempts: u32,
+    // Error of the last failed attempt or reason for skipping
+    pub last_error: Option<String>,
+    // Unix timestamp of the first time the tweet was seen
+    pub created_at: u64,
+    // Unix timestamp of the last status change
+    pub updated_at: u64,
+    // Artifacts produced for the tweet
+    #[serde(default)]
+    pub output: TweetOutput,
+}
+
+// Artifacts produced while answering a tweet, each stage checkpoints its result so a retry
+// resumes after the last completed one
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TweetOutput {
+    // Avatar labels, most relevant first
+    pub labels: Option<Vec<String>>,
+    // Image generation prompt written from the labels
+    pub prompt: Option<String>,
+    // Path of the generated artwork on disk
+    pub image_path: Option<String>,
+    // ID of the tweet carrying the artwork
+    pub reply_id: Option<String>,
+}
+
+// Records removed by a pruning pass
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    // Removed records, oldest first
+    pub removed: Vec<TweetRecord>,
+}
+
+impl PruneReport {
+    // Number of removed records with the given status
+    pub fn count(&self, status: TweetStatus) -> usize {
+        self.removed.iter().filter(|record| record.status == status).count()
+    }
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pruned {} records (done: {}, skipped: {}, failed: {})",
+            self.removed.len(),
+            self.count(TweetStatus::Done),
+            self.count(TweetStatus::Skipped),
+            self.count(TweetStatus::Failed)
+        )
+    }
+}
+
+// Backend kinds for persistent storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    // Single JSON file rewritten on every flush
+    Json,
+    // Embedded SQLite database
+    Sqlite,
+}
+
+impl FromStr for StorageBackend {
+    type Err = LeoError;
+
+    fn from_str(backend: &str) -> Result<Self> {
+        match backend {
+            "json" => Ok(StorageBackend::Json),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            _ => Err(LeoError::Config(format!("Unknown storage backend {}", backend))),
+        }
+    }
+}
+
+impl TweetStatus {
+    // Name used when persisting the status
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TweetStatus::Pending => "pending",
+            TweetStatus::InProgress => "in_progress",
+            TweetStatus::Done => "done",
+            TweetStatus::Failed => "failed",
+            TweetStatus::Skipped => "skipped",
+        }
+    }
+
+    // Parse a persisted status name
+    pub fn parse(status: &str) -> Result<Self> {
+        match status {
+            "pending" => Ok(TweetStatus::Pending),
+            "in_progress" => Ok(TweetStatus::InProgress),
+            "done" => Ok(TweetStatus::Done),
+            "failed" => Ok(TweetStatus::Failed),
+            "skipped" => Ok(TweetStatus::Skipped),
+            _ => Err(LeoError::Storage(format!("Unknown tweet status {}", status))),
+        }
+    }
+}
+
+impl TweetRecord {
+    // Create a pending record for a newly seen tweet
+    pub fn new(id: String, tweet: ExtractedTweet) -> Self {
+        let now = now_secs();
+        Self {
+            id,
+            tweet,
+            status: TweetStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+            output: TweetOutput::default(),
+        }
+    }
+
+    // Check whether the record still has to be processed
+    pub fn is_processable(&self) -> bool {
+        matches!(self.status, TweetStatus::Pending | TweetStatus::InProgress)
+    }
+
+    // Check whether the record is finished and unchanged since before the cutoff
+    pub fn is_expired(&self, cutoff: u64) -> bool {
+        !self.is_processable() && self.updated_at < cutoff
+    }
+
+    // Change the status and bump the update timestamp
+    fn set_status(&mut self, status: TweetStatus) {
+        self.status = status;
+        self.updated_at = now_secs();
+    }
+}
+
+// Skip reason of mentions older than the configured age, a backfill answers them anyway
+pub const STALE_MENTION: &str = "Mention too old";
+
+// Persistent store of tweet processing records
+pub trait Storage: Send {
+    // Get the record of a tweet
+    fn get(&self, tweet: &str) -> Result<Option<TweetRecord>>;
+
+    // Insert or replace a record
+    fn put(&mut self, record: TweetRecord) -> Result<()>;
+
+    // Remove a record, returns true if it was present
+    fn remove(&mut self, tweet: &str) -> Result<bool>;
+
+    // Records with the given status, oldest first
+    fn with_status(&self, status: TweetStatus) -> Result<Vec<TweetRecord>>;
+
+    // ID of the newest mention already recorded, searches stop once they reach it
+    fn since_id(&self) -> Result<Option<String>>;
+
+    // Move the high-water mark of recorded mentions
+    fn set_since_id(&mut self, since_id: &str) -> Result<()>;
+
+    // Make every change durable
+    fn flush(&mut self) -> Result<()>;
+
+    // Reclaim space left by removed records
+    fn compact(&mut self) -> Result<()> {
+        self.flush()
+    }
+
+    // Record a tweet returned by the search, returns true if it still has to be processed
+    fn track(&mut self, tweet: &ExtractedTweet) -> Result<bool> {
+        let Some(id) = tweet.id.clone() else {
+            return Ok(false);
+        };
+
+        match self.get(&id)? {
+            Some(record) => Ok(record.is_processable()),
+            None => {
+                self.put(TweetRecord::new(id, tweet.clone()))?;
+                Ok(true)
+            }
+        }
+    }
+
+    // Record a tweet found by a backfill, returns true if it still has to be processed. Mentions
+    // skipped for their age are queued again, a backfill answers them regardless of it
+    fn track_backfill(&mut self, tweet: &ExtractedTweet) -> Result<bool> {
+        let Some(id) = tweet.id.as_deref() else {
+            return Ok(false);
+        };
+
+        match self.get(id)? {
+            Some(mut record)
+                if record.status == TweetStatus::Skipped && record.last_error.as_deref() == Some(STALE_MENTION) =>
+            {
+                record.last_error = None;
+                record.set_status(TweetStatus::Pending);
+                self.put(record)?;
+                Ok(true)
+            }
+            _ => self.track(tweet),
+        }
+    }
+
+    // Tweets waiting for a first attempt or a retry
+    fn retryable(&self) -> Result<Vec<ExtractedTweet>> {
+        let mut records = self.with_status(TweetStatus::Pending)?;
+        records.extend(self.with_status(TweetStatus::InProgress)?);
+        Ok(records.into_iter().map(|record| record.tweet).collect())
+    }
+
+    // Mark a tweet as picked up by a worker
+    fn start(&mut self, tweet: &str) -> Result<()> {
+        self.update(tweet, &|record| record.set_status(TweetStatus::InProgress))
+    }
+
+    // Put a tweet back in the queue without counting an attempt
+    fn release(&mut self, tweet: &str) -> Result<()> {
+        self.update(tweet, &|record| record.set_status(TweetStatus::Pending))
+    }
+
+    // Save the stage outputs of a tweet still being processed
+    fn checkpoint(&mut self, tweet: &str, output: TweetOutput) -> Result<()> {
+        self.update(tweet, &|record| record.output = output.clone())
+    }
+
+    // Mark a tweet as answered and keep references to what was produced
+    fn complete(&mut self, tweet: &str, output: TweetOutput) -> Result<()> {
+        self.update(tweet, &|record| {
+            record.output = output.clone();
+            record.last_error = None;
+            record.set_status(TweetStatus::Done);
         })
     }
 
-    // Save current storage state to file
-    pub fn write_to_file(&self) -> io::Result<()> {
-        // Open file with write permissions, create if doesn't exist
-        let file = OpenOptions::new()
-            .write(true)
+    // Mark a tweet as not needing an answer
+    fn skip(&mut self, tweet: &str, reason: &str) -> Result<()> {
+        self.update(tweet, &|record| {
+            record.last_error = Some(reason.to_string());
+            record.set_status(TweetStatus::Skipped);
+        })
+    }
+
+    // Record a failed attempt, moving the tweet to the dead letters when it cannot be retried
+    fn fail(&mut self, tweet: &str, error: &LeoError, max_attempts: u32) -> Result<()> {
+        self.update(tweet, &|record| {
+            record.attempts += 1;
+            record.last_error = Some(error.to_string());
+            if error.is_permanent() || record.attempts >= max_attempts {
+                record.set_status(TweetStatus::Failed);
+            } else {
+                record.set_status(TweetStatus::Pending);
+            }
+        })
+    }
+
+    // Tweets that were given up on, oldest first
+    fn dead_letters(&self) -> Result<Vec<TweetRecord>> {
+        self.with_status(TweetStatus::Failed)
+    }
+
+    // Move a dead letter back to the queue with a fresh attempt budget
+    fn requeue(&mut self, tweet: &str) -> Result<bool> {
+        match self.get(tweet)? {
+            Some(mut record) if record.status == TweetStatus::Failed => {
+                record.attempts = 0;
+                record.set_status(TweetStatus::Pending);
+                self.put(record)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Remove finished records unchanged since before the cutoff, except the ones still returned by
+    // the mention search which would otherwise be picked up again as new. Space is only reclaimed
+    // by `compact`
+    fn prune(&mut self, cutoff: u64, keep: &HashSet<String>) -> Result<PruneReport> {
+        let mut removed = Vec::new();
+        for status in [TweetStatus::Done, TweetStatus::Skipped, TweetStatus::Failed] {
+            for record in self.with_status(status)? {
+                if record.is_expired(cutoff) && !keep.contains(&record.id) && self.remove(&record.id)? {
+                    removed.push(record);
+                }
+            }
+        }
+        removed.sort_by_key(|record| record.updated_at);
+
+        // Compacting rewrites the whole state, it is left to the prune command
+        if !removed.is_empty() {
+            self.flush()?;
+        }
+
+        Ok(PruneReport { removed })
+    }
+
+    // Apply a change to an existing record
+    fn update(&mut self, tweet: &str, change: &dyn Fn(&mut TweetRecord)) -> Result<()> {
+        if let Some(mut record) = self.get(tweet)? {
+            change(&mut record);
+            self.put(record)?;
+        }
+        Ok(())
+    }
+}
+
+// Exclusive lock on `<state file>.lock` so two bot processes never share the same state
+pub struct StateLock {
+    // Locked file, the lock is released when it is closed
+    _file: File,
+}
+
+impl StateLock {
+    // Lock the state file or fail if another process holds it
+    pub fn acquire(state_path: &str) -> Result<Self> {
+        let path = format!("{}.lock", state_path);
+        let mut file = OpenOptions::new()
             .create(true)
-            .truncate(true)
-            .open(&self.file_path)?;
-        // Create buffered writer for efficient writing
-        let writer = BufWriter::new(file);
-        // Serialize and write storage to file
-        serde_json::to_writer(writer, &self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|err| LeoError::Storage(format!("Unable to open {} {}", path, err)))?;
+
+        file.try_lock().map_err(|err| match err {
+            TryLockError::WouldBlock => LeoError::Storage(format!("{} is held by another bot process", path)),
+            TryLockError::Error(err) => LeoError::Storage(format!("Unable to lock {} {}", path, err)),
+        })?;
+
+        // Record the owner to help diagnosing a held lock
+        file.set_len(0)?;
+        write!(file, "{}", process::id())?;
+
+        Ok(Self { _file: file })
     }
+}
 
-    // Insert new item into storage
-    pub fn add(&mut self, tweet: String) -> bool {
-        // Returns true if item was newly inserted
-        self.items.insert(tweet)
+// Open the configured storage backend
+pub fn open(config: &BotConfig) -> Result<Box<dyn Storage>> {
+    match config.storage_backend {
+        StorageBackend::Json => Ok(Box::new(JsonStorage::read_from_file(
+            &config.storage_file,
+            config.recover_state,
+        )?)),
+        StorageBackend::Sqlite => Ok(Box::new(SqliteStorage::open(&config.storage_file)?)),
     }
+}
 
-    // Check if item exists in storage
-    pub fn exists(&mut self, tweet: String) -> bool {
-        // Returns true if item exists
-        self.items.contains(&tweet)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+
+    fn tweet(id: &str) -> ExtractedTweet {
+        ExtractedTweet {
+            id: Some(id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn status(storage: &MemoryStorage, id: &str) -> TweetStatus {
+        storage.get(id).unwrap().unwrap().status
+    }
+
+    // Move the last change of a record into the past
+    fn age(storage: &mut MemoryStorage, id: &str, updated_at: u64) {
+        let mut record = storage.get(id).unwrap().unwrap();
+        record.updated_at = updated_at;
+        storage.put(record).unwrap();
+    }
+
+    #[test]
+    fn tracks_new_tweets_once() {
+        let mut storage = MemoryStorage::new();
+
+        assert!(storage.track(&tweet("1")).unwrap());
+        assert!(!storage.track(&ExtractedTweet::default()).unwrap());
+        assert_eq!(status(&storage, "1"), TweetStatus::Pending);
+
+        // Seen again while still queued, then once answered
+        assert!(storage.track(&tweet("1")).unwrap());
+        storage.complete("1", TweetOutput::default()).unwrap();
+        assert!(!storage.track(&tweet("1")).unwrap());
+        assert_eq!(status(&storage, "1"), TweetStatus::Done);
+    }
+
+    #[test]
+    fn fails_after_max_attempts() {
+        let mut storage = MemoryStorage::new();
+        storage.track(&tweet("1")).unwrap();
+        let error = LeoError::Http("timeout".into());
+
+        storage.start("1").unwrap();
+        assert_eq!(status(&storage, "1"), TweetStatus::InProgress);
+        storage.fail("1", &error, 2).unwrap();
+        assert_eq!(status(&storage, "1"), TweetStatus::Pending);
+        assert_eq!(storage.retryable().unwrap().len(), 1);
+
+        storage.start("1").unwrap();
+        storage.fail("1", &error, 2).unwrap();
+        let record = storage.get("1").unwrap().unwrap();
+        assert_eq!(record.status, TweetStatus::Failed);
+        assert_eq!(record.attempts, 2);
+        assert_eq!(record.last_error, Some(error.to_string()));
+        assert!(storage.retryable().unwrap().is_empty());
+        assert_eq!(storage.dead_letters().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn fails_permanent_errors_at_once() {
+        let mut storage = MemoryStorage::new();
+        storage.track(&tweet("1")).unwrap();
+
+        storage.fail("1", &LeoError::PolicyRejection("refused".into()), 3).unwrap();
+        assert_eq!(status(&storage, "1"), TweetStatus::Failed);
+    }
+
+    #[test]
+    fn release_keeps_attempts() {
+        let mut storage = MemoryStorage::new();
+        storage.track(&tweet("1")).unwrap();
+
+        storage.start("1").unwrap();
+        storage.release("1").unwrap();
+        let record = storage.get("1").unwrap().unwrap();
+        assert_eq!(record.status, TweetStatus::Pending);
+        assert_eq!(record.attempts, 0);
+    }
+
+    #[test]
+    fn requeues_dead_letters_only() {
+        let mut storage = MemoryStorage::new();
+        storage.track(&tweet("1")).unwrap();
+        storage.track(&tweet("2")).unwrap();
+        storage.fail("1", &LeoError::Http("timeout".into()), 1).unwrap();
+
+        assert!(storage.requeue("1").unwrap());
+        let record = storage.get("1").unwrap().unwrap();
+        assert_eq!(record.status, TweetStatus::Pending);
+        assert_eq!(record.attempts, 0);
+
+        assert!(!storage.requeue("2").unwrap());
+        assert!(!storage.requeue("3").unwrap());
     }
 
-    // Remove item from storage
-    pub fn delete(&mut self, tweet: String) -> bool {
-        // Returns true if item was present and removed
-        self.items.remove(&tweet)
+    #[test]
+    fn backfill_queues_stale_mentions_again() {
+        let mut storage = MemoryStorage::new();
+        storage.track(&tweet("1")).unwrap();
+        storage.skip("1", STALE_MENTION).unwrap();
+        storage.track(&tweet("2")).unwrap();
+        storage.skip("2", "No avatar").unwrap();
+
+        assert!(!storage.track(&tweet("1")).unwrap());
+        assert!(storage.track_backfill(&tweet("1")).unwrap());
+        assert_eq!(status(&storage, "1"), TweetStatus::Pending);
+        assert!(!storage.track_backfill(&tweet("2")).unwrap());
+        assert_eq!(status(&storage, "2"), TweetStatus::Skipped);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn prunes_expired_finished_records() {
+        let mut storage = MemoryStorage::new();
+        for id in ["1", "2", "3", "4", "5"] {
+            storage.track(&tweet(id)).unwrap();
+        }
+        storage.complete("1", TweetOutput::default()).unwrap();
+        storage.skip("2", "No avatar").unwrap();
+        storage.complete("3", TweetOutput::default()).unwrap();
+        storage.complete("4", TweetOutput::default()).unwrap();
+        for id in ["1", "2", "3", "5"] {
+            age(&mut storage, id, 10);
+        }
+        let keep = HashSet::from(["3".to_string()]);
+
+        let report = storage.prune(100, &keep).unwrap();
+        assert_eq!(report.removed.len(), 2);
+        assert_eq!(report.count(TweetStatus::Done), 1);
+        assert_eq!(report.count(TweetStatus::Skipped), 1);
+        assert!(storage.get("1").unwrap().is_none());
+        assert!(storage.get("2").unwrap().is_none());
+        // Still returned by the search, changed after the cutoff, or not finished
+        assert!(storage.get("3").unwrap().is_some());
+        assert!(storage.get("4").unwrap().is_some());
+        assert!(storage.get("5").unwrap().is_some());
+    }
+}
@@ -1,9 +1,36 @@
-use std::{env, process};
-use agent_twitter_client::{models::Profile, scraper::Scraper, search::SearchMode};
-use log::error;
-use anyhow::Result;
+use std::cmp::Ordering;
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+use agent_twitter_client::{
+    auth::user_auth::TwitterUserAuth,
+    error::{Result as ScraperResult, TwitterError},
+    models::Profile,
+    scraper::Scraper,
+    search::SearchMode,
+};
+use async_trait::async_trait;
+use tokio::sync::RwLock;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use crate::config::TwitterConfig;
+use crate::error::{LeoError, Result};
+use crate::image::Image;
+use crate::pipeline::{MentionSource, Publisher};
+
+// Error code returned when the author restricted who can reply to a tweet
+const REPLY_RESTRICTED_CODE: i64 = 433;
+// Error code returned when the account exceeded its request rate
+const RATE_LIMIT_CODE: i64 = 88;
+// Size suffixes Twitter appends to avatar file names
+const AVATAR_SIZES: [&str; 5] = ["_400x400", "_200x200", "_bigger", "_normal", "_mini"];
+// Sizes tried after the original upload, largest first
+const AVATAR_FALLBACKS: [&str; 3] = ["_400x400", "_200x200", "_normal"];
+// Largest banner size Twitter serves
+const BANNER_SIZE: &str = "1500x500";
 
 // Main Twitter client struct
 pub struct Twitter {
@@ -13,12 +40,16 @@ pub struct Twitter {
     pub password: String,
     // Email associated with the Twitter account
     pub email: String,
-    // Instance of the Twitter scraper
-    pub scraper: Scraper,
+    // Account settings used to log in again when the session expires
+    config: TwitterConfig,
+    // Logged in scraper, replaced when the session expires
+    scraper: RwLock<Arc<Scraper>>,
+    // Incremented on every login so concurrent callers log in again only once
+    session: AtomicU64,
 }
 
 // Structure representing extracted tweet data
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ExtractedTweet {
     // Display name of the user
     pub name: Option<String>,
@@ -36,68 +67,287 @@ pub struct ExtractedTweet {
     pub id: Option<String>,
 }
 
-impl Twitter {
-    // Initialize a new Twitter client instance
-    pub async fn initialize() -> Result<Self> {
-        // Retrieve Twitter credentials from environment variables
-        let username = env::var("TWITTER_USERNAME").unwrap_or_else(|err| {
-            error!("Missing TWITTER_USERNAME {}", err);
-            process::exit(1);
-        });
-
-        let password = env::var("TWITTER_PASSWORD").unwrap_or_else(|err| {
-            error!("Missing TWITTER_PASSWORD {}", err);
-            process::exit(1);
-        });
-
-        let email = env::var("TWITTER_EMAIL").unwrap_or_else(|err| {
-            error!("Missing TWITTER_EMAIL {}", err);
-            process::exit(1);
-        });
-
-        let two_factor_secret = env::var("TWITTER_2FA_CODE").unwrap_or_else(|err| {
-            error!("Missing TWITTER_2FA_CODE {}", err);
-            process::exit(1);
-        });
-
-        let two_factor_secret: Option<String> = if two_factor_secret.is_empty() {
-            None
-        } else {
-            Some(two_factor_secret)
-        };
+impl ExtractedTweet {
+    // Check whether the tweet was posted after the tweet with the given ID
+    pub fn is_newer_than(&self, since_id: &str) -> bool {
+        self.id.as_deref().is_some_and(|id| compare_ids(id, since_id).is_gt())
+    }
+}
 
-        // Initialize and log in to Twitter
-        let mut scraper = Scraper::new().await?;
+// Order tweet IDs chronologically, IDs are decimal snowflakes of growing length
+pub fn compare_ids(a: &str, b: &str) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
 
-        scraper
-            .login(
-                username.clone(),
-                password.clone(),
-                Some(email.clone()),
-                two_factor_secret,
-            )
-            .await?;
+// Single page of search results
+#[derive(Debug, Clone, Default)]
+pub struct TweetPage {
+    // Tweets of the page, newest first
+    pub tweets: Vec<ExtractedTweet>,
+    // Cursor of the next, older page, None on the last page
+    pub next: Option<String>,
+}
+
+// Image URLs of a profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileImages {
+    // Avatar URLs from the original upload down to the thumbnail, later ones are fallbacks
+    pub avatars: Vec<String>,
+    // Banner URL, None when the user has not set one
+    pub banner: Option<String>,
+}
+
+// Profile of a tweet author
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserProfile {
+    // Username with the casing Twitter uses
+    pub username: String,
+    // Avatar and banner URLs, None without an avatar
+    pub images: Option<ProfileImages>,
+}
+
+// Expand an avatar URL into its sizes, largest first. Profiles return the 48x48 `_normal` thumbnail
+fn avatar_variants(url: &str) -> Vec<String> {
+    // Split the extension off the file name, it may be missing
+    let name_start = url.rfind('/').map_or(0, |slash| slash + 1);
+    let (stem, extension) = match url[name_start..].rfind('.') {
+        Some(dot) => url.split_at(name_start + dot),
+        None => (url, ""),
+    };
+
+    // URLs without a size suffix are used as given
+    let Some(base) = AVATAR_SIZES.iter().find_map(|size| stem.strip_suffix(size)) else {
+        return vec![url.to_string()];
+    };
+
+    let mut variants = vec![format!("{}{}", base, extension)];
+    variants.extend(AVATAR_FALLBACKS.iter().map(|size| format!("{}{}{}", base, size, extension)));
+    variants
+}
+
+// Request the largest banner size, profiles return the banner URL without one
+fn banner_variant(url: &str) -> String {
+    let url = url.trim_end_matches('/');
+    let last = url.rsplit('/').next().unwrap_or_default();
+    if last.contains('x') {
+        url.to_string()
+    } else {
+        format!("{}/{}", url, BANNER_SIZE)
+    }
+}
+
+// How a tweet produced by the bot was delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryMode {
+    // Threaded reply under the originating tweet
+    Reply,
+    // Quote tweet of the originating tweet
+    Quote,
+    // Standalone tweet mentioning the requester
+    Mention,
+}
+
+// Structure representing a tweet posted by the bot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostedTweet {
+    // Unique identifier of the posted tweet
+    pub id: String,
+    // How the tweet was delivered
+    pub mode: DeliveryMode,
+}
+
+impl PostedTweet {
+    // Extract the posted tweet from a create tweet response
+    fn from_response(response: &Value, mode: DeliveryMode) -> Result<Self> {
+        if let Some(errors) = response["errors"].as_array() {
+            let messages: Vec<&str> = errors.iter().filter_map(|error| error["message"].as_str()).collect();
+            let message = format!("Tweet rejected: {}", messages.join("; "));
+            if errors.iter().any(|error| error["code"].as_i64() == Some(RATE_LIMIT_CODE)) {
+                return Err(LeoError::RateLimited(message));
+            }
+            return Err(LeoError::Twitter(message));
+        }
+
+        let id = response["data"]["create_tweet"]["tweet_results"]["result"]["rest_id"]
+            .as_str()
+            .ok_or_else(|| LeoError::Decode(format!("Missing tweet id in response: {}", response)))?;
+
+        Ok(Self { id: id.to_string(), mode })
+    }
+}
+
+// Check whether a create tweet response was refused because replies are restricted
+fn is_reply_restricted(response: &Value) -> bool {
+    response["errors"]
+        .as_array()
+        .map(|errors| errors.iter().any(|error| error["code"].as_i64() == Some(REPLY_RESTRICTED_CODE)))
+        .unwrap_or(false)
+}
+
+// Convert a scraper failure into a library error
+fn twitter_error(err: TwitterError) -> LeoError {
+    match err {
+        TwitterError::RateLimit => LeoError::RateLimited(err.to_string()),
+        TwitterError::Auth(message) => LeoError::Auth(message),
+        // Failed requests carry their status only in the message
+        TwitterError::Api(message) => match failed_status(&message) {
+            Some(401) => LeoError::Auth(message),
+            Some(429) => LeoError::RateLimited(message),
+            _ => LeoError::Twitter(message),
+        },
+        TwitterError::Network(err) => LeoError::Http(err.to_string()),
+        err => LeoError::Twitter(err.to_string()),
+    }
+}
+
+// Extract the status of a `Request failed with status: 429 Too Many Requests` message
+fn failed_status(message: &str) -> Option<u16> {
+    let status = message.strip_prefix("Request failed with status: ")?;
+    status.split_whitespace().next()?.parse().ok()
+}
+
+// Load the saved cookies into a new scraper and check that the session is still accepted
+async fn restore_session(config: &TwitterConfig) -> Result<Option<Scraper>> {
+    if config.cookie_file.is_empty() || !Path::new(&config.cookie_file).is_file() {
+        return Ok(None);
+    }
+
+    let cookies = fs::read_to_string(&config.cookie_file)?;
+    let mut scraper = Scraper::new().await.map_err(twitter_error)?;
+    scraper.set_from_cookie_string(cookies.trim()).await.map_err(twitter_error)?;
+
+    let auth = scraper
+        .twitter_client
+        .auth
+        .as_any()
+        .downcast_ref::<TwitterUserAuth>()
+        .ok_or_else(|| LeoError::Auth("Scraper has no user session".into()))?;
+    auth.is_logged_in(&scraper.twitter_client.client).await.map_err(twitter_error)?;
+
+    Ok(Some(scraper))
+}
+
+// Log in with the account credentials and save the new session
+async fn login(config: &TwitterConfig) -> Result<Scraper> {
+    let two_factor_secret: Option<String> = if config.two_factor_secret.is_empty() {
+        None
+    } else {
+        Some(config.two_factor_secret.clone())
+    };
+
+    let mut scraper = Scraper::new().await.map_err(twitter_error)?;
+    scraper
+        .login(
+            config.username.clone(),
+            config.password.clone(),
+            Some(config.email.clone()),
+            two_factor_secret,
+        )
+        .await
+        .map_err(|err| LeoError::Auth(err.to_string()))?;
+
+    // A session that cannot be saved only costs a login on the next start
+    if let Err(err) = save_session(config, &scraper).await {
+        println!("Unable to save Twitter session to {}: {}", config.cookie_file, err);
+    }
+
+    Ok(scraper)
+}
+
+// Write the session cookies, readable by the owner only since they grant account access
+async fn save_session(config: &TwitterConfig, scraper: &Scraper) -> Result<()> {
+    if config.cookie_file.is_empty() {
+        return Ok(());
+    }
+
+    let cookies = scraper.get_cookie_string().await.map_err(twitter_error)?;
+    let tmp_path = format!("{}.tmp", config.cookie_file);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(cookies.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &config.cookie_file)?;
+
+    Ok(())
+}
+
+impl Twitter {
+    // Initialize a new Twitter client instance, reusing the saved session when it is still valid
+    pub async fn initialize(config: &TwitterConfig) -> Result<Self> {
+        let scraper = match restore_session(config).await {
+            Ok(Some(scraper)) => {
+                println!("Restored Twitter session from {}", config.cookie_file);
+                scraper
+            }
+            Ok(None) => login(config).await?,
+            Err(err) => {
+                println!("Saved Twitter session is unusable ({}), logging in", err);
+                login(config).await?
+            }
+        };
 
         Ok(Self {
-            username,
-            password,
-            email,
-            scraper,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            email: config.email.clone(),
+            config: config.clone(),
+            scraper: RwLock::new(Arc::new(scraper)),
+            session: AtomicU64::new(0),
         })
     }
 
-    // Search for tweets matching a query
+    // Run a scraper call, logging in again and retrying once when the session was rejected
+    async fn call<T, F, Fut>(&self, op: F) -> Result<T>
+    where
+        F: Fn(Arc<Scraper>) -> Fut,
+        Fut: Future<Output = ScraperResult<T>>,
+    {
+        let session = self.session.load(atomic::Ordering::SeqCst);
+        let scraper = self.scraper.read().await.clone();
+
+        match op(scraper).await.map_err(twitter_error) {
+            Err(LeoError::Auth(err)) => {
+                self.relogin(session, &err).await?;
+                let scraper = self.scraper.read().await.clone();
+                op(scraper).await.map_err(twitter_error)
+            }
+            result => result,
+        }
+    }
+
+    // Replace an expired session, unless another caller already did since `session` was read
+    async fn relogin(&self, session: u64, reason: &str) -> Result<()> {
+        let mut scraper = self.scraper.write().await;
+        if self.session.load(atomic::Ordering::SeqCst) != session {
+            return Ok(());
+        }
+
+        println!("Twitter session rejected ({}), logging in again", reason);
+        *scraper = Arc::new(login(&self.config).await?);
+        self.session.fetch_add(1, atomic::Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    // Search for a page of tweets matching a query, continuing from the cursor of the previous page
     pub async fn find_tweets(
         &self,
         query: &str,
         max_tweets: i32,
         search_mode: Option<SearchMode>,
         cursor: Option<String>,
-    ) -> Result<Vec<ExtractedTweet>> {
+    ) -> Result<TweetPage> {
         // Execute tweet search
+        let search_mode = search_mode.unwrap_or(SearchMode::Latest);
         let tweets = self
-            .scraper
-            .search_tweets(query, max_tweets, search_mode.unwrap_or(SearchMode::Latest), cursor)
+            .call(|scraper| {
+                let cursor = cursor.clone();
+                async move { scraper.search_tweets(query, max_tweets, search_mode, cursor).await }
+            })
             .await?;
 
         // Convert tweets to ExtractedTweet format
@@ -115,18 +365,26 @@ impl Twitter {
             })
             .collect();
 
-        Ok(extracted_tweets)
+        // An empty page ends the results even when a cursor is returned
+        let next = tweets.next.filter(|_| !extracted_tweets.is_empty());
+
+        Ok(TweetPage {
+            tweets: extracted_tweets,
+            next,
+        })
     }
 
     // Retrieve user profile information
     pub async fn fetch_profile(&self, username: &str) -> Result<Profile> {
-        let profile = self.scraper.get_profile(username).await?;
-        Ok(profile)
+        self.call(|scraper| async move { scraper.get_profile(username).await }).await
     }
 
-    // Retrieve user's avatar URL from profile
-    pub async fn fetch_avatar(&self, profile: Profile) -> Result<Option<String>> {
-        Ok(profile.profile_image_url)
+    // Retrieve user's avatar and banner URLs from profile, None without an avatar
+    pub async fn fetch_profile_images(&self, profile: Profile) -> Result<Option<ProfileImages>> {
+        Ok(profile.profile_image_url.map(|avatar| ProfileImages {
+            avatars: avatar_variants(&avatar),
+            banner: profile.profile_banner_url.as_deref().map(banner_variant),
+        }))
     }
 
     // Post a new tweet with optional media
@@ -135,8 +393,107 @@ impl Twitter {
         text: &str,
         reply_to: Option<&str>,
         media_data: Option<Vec<(Vec<u8>, String)>>,
-    ) -> Result<Value> {
-        let tweet_with_media = self.scraper.send_tweet(text, reply_to, media_data).await?;
-        Ok(tweet_with_media)
+    ) -> Result<PostedTweet> {
+        let response = self
+            .call(|scraper| {
+                let media_data = media_data.clone();
+                async move { scraper.send_tweet(text, reply_to, media_data).await }
+            })
+            .await?;
+        let mode = if reply_to.is_some() { DeliveryMode::Reply } else { DeliveryMode::Mention };
+        PostedTweet::from_response(&response, mode)
+    }
+
+    // Post a quote tweet of an existing tweet with optional media
+    pub async fn quote_tweet(
+        &self,
+        text: &str,
+        quoted_id: &str,
+        media_data: Option<Vec<(Vec<u8>, String)>>,
+    ) -> Result<PostedTweet> {
+        let response = self
+            .call(|scraper| {
+                let media_data = media_data.clone();
+                async move { scraper.send_quote_tweet(text, quoted_id, media_data).await }
+            })
+            .await?;
+        PostedTweet::from_response(&response, DeliveryMode::Quote)
+    }
+
+    // Reply to a tweet, falling back to a quote tweet or a mention when replies are restricted
+    pub async fn reply_to_tweet(
+        &self,
+        text: &str,
+        tweet_id: &str,
+        username: &str,
+        media_data: Option<Vec<(Vec<u8>, String)>>,
+    ) -> Result<PostedTweet> {
+        let response = self
+            .call(|scraper| {
+                let media_data = media_data.clone();
+                async move { scraper.send_tweet(text, Some(tweet_id), media_data).await }
+            })
+            .await?;
+        if !is_reply_restricted(&response) {
+            return PostedTweet::from_response(&response, DeliveryMode::Reply);
+        }
+
+        println!("Replies to tweet {} are restricted. Quoting instead", tweet_id);
+        // Failed calls, throttling and rejected sessions are returned, another post would fail too
+        let response = self
+            .call(|scraper| {
+                let media_data = media_data.clone();
+                async move { scraper.send_quote_tweet(text, tweet_id, media_data).await }
+            })
+            .await?;
+        match PostedTweet::from_response(&response, DeliveryMode::Quote) {
+            // Only a quote Twitter refused is worth a mention
+            Err(LeoError::Twitter(reason)) => {
+                println!("Quote of tweet {} failed: {}. Mentioning instead", tweet_id, reason);
+                let mention_text = format!("@{} {}", username, text);
+                self.post_tweet(&mention_text, None, media_data).await
+            }
+            posted => posted,
+        }
     }
 }
+
+// Implementation of MentionSource trait searching with the scraper
+#[async_trait]
+impl MentionSource for Twitter {
+    fn username(&self) -> &str {
+        &self.username
+    }
+
+    async fn search(&self, query: &str, max_tweets: i32, cursor: Option<String>) -> Result<TweetPage> {
+        self.find_tweets(query, max_tweets, None, cursor).await
+    }
+
+    async fn lookup_profile(&self, username: &str) -> Result<UserProfile> {
+        let profile = self.fetch_profile(username).await?;
+        Ok(UserProfile {
+            username: profile.username.clone(),
+            images: self.fetch_profile_images(profile).await?,
+        })
+    }
+}
+
+// Implementation of Publisher trait replying under the mention
+#[async_trait]
+impl Publisher for Twitter {
+    // Reply to the tweet with the image attached
+    async fn publish(&self, tweet: &ExtractedTweet, text: &str, image: &Image) -> Result<PostedTweet> {
+        let media_data = vec![(image.bytes().to_vec(), image.format.mime_type().to_string())];
+
+        let tweet_id = tweet
+            .id
+            .as_deref()
+            .ok_or_else(|| LeoError::Decode("Tweet has no id".into()))?;
+        let username = tweet
+            .username
+            .as_deref()
+            .ok_or_else(|| LeoError::Decode("Tweet has no username".into()))?;
+
+        self.reply_to_tweet(text, tweet_id, username, Some(media_data)).await
+    }
+}
\ No newline at end of file
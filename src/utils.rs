@@ -1,38 +1,110 @@
-use std::{env, fs, path::PathBuf};
+use std::{
+    env,
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use directories_next::ProjectDirs;
 use uuid::Uuid;
 
-// Generate a custom image path in the current working directory
-pub fn generate_custom_image_path() -> PathBuf {
+use crate::error::{LeoError, Result};
+
+// Current Unix timestamp in seconds
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+// Unix timestamp before which records fall outside a retention window of the given days
+pub fn retention_cutoff(days: u64) -> u64 {
+    now_secs().saturating_sub(days.saturating_mul(24 * 60 * 60))
+}
+
+// Parse a `YYYY-MM-DD` date into the Unix timestamp of its UTC midnight
+pub fn parse_date(date: &str) -> Result<u64> {
+    let invalid = || LeoError::Config(format!("Invalid date {}, expected YYYY-MM-DD", date));
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<i64>().map_err(|_| invalid()));
+    let (Some(year), Some(month), Some(day)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let (year, month, day) = (year?, month?, day?);
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return Err(invalid());
+    }
+
+    // Days since the epoch of a proleptic Gregorian date, with years starting in March so the
+    // leap day comes last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Ok(days as u64 * 24 * 60 * 60)
+}
+
+// Number of days of a month in the Gregorian calendar
+fn days_in_month(year: i64, month: i64) -> i64 {
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Format the UTC date of a Unix timestamp as `YYYY-MM-DD`
+pub fn format_date(timestamp: u64) -> String {
+    // Inverse of the day count in `parse_date`
+    let days = (timestamp / (24 * 60 * 60)) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Generate a custom image path with the given extension in the current working directory
+pub fn generate_custom_image_path(extension: &str) -> Result<PathBuf> {
     // Obtain the current working directory
-    let current_dir = env::current_dir().unwrap();
+    let current_dir = env::current_dir()?;
     let image_dir = current_dir.join("images");
 
     // Create the images directory if it doesn't already exist
     if !image_dir.exists() {
-        fs::create_dir_all(&image_dir).unwrap();
+        fs::create_dir_all(&image_dir)?;
     }
 
     // Generate a unique filename using a UUID
-    let unique_file_name = format!("image-{}.png", Uuid::new_v4());
+    let unique_file_name = format!("image-{}.{}", Uuid::new_v4(), extension);
     let unique_path = image_dir.join(unique_file_name);
 
-    unique_path
+    Ok(unique_path)
 }
 
-// Generate an image path in the application data directory
-pub fn create_image_path() -> PathBuf {
+// Generate an image path with the given extension in the application data directory
+pub fn create_image_path(extension: &str) -> Result<PathBuf> {
     // Retrieve the application-specific directory
-    let main_dirs = ProjectDirs::from("", "", "leo").unwrap();
+    let main_dirs = ProjectDirs::from("", "", "leo")
+        .ok_or_else(|| LeoError::Storage("Unable to resolve application data directory".into()))?;
     let image_dir = main_dirs.data_local_dir().join("images");
 
     // Create the images directory if it doesn't already exist
-    fs::create_dir_all(&image_dir).ok().unwrap();
+    fs::create_dir_all(&image_dir)?;
 
     // Generate a unique filename using a UUID
-    let unique_file_name = format!("image-{}.png", Uuid::new_v4());
+    let unique_file_name = format!("image-{}.{}", Uuid::new_v4(), extension);
     let unique_path = image_dir.join(unique_file_name);
 
-    unique_path
+    Ok(unique_path)
 }
@@ -1,16 +1,28 @@
-use anyhow::Result;
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::time::{SystemTime, UNIX_EPOCH};
-use ureq::json;
-use crate::{http_client::HttpClient, image::Image};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde_json::json;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use crate::{
+    config::{HttpConfig, VisionConfig},
+    error::{ApiError, LeoError, Result},
+    http_client::HttpClient,
+    image::Image,
+    pipeline::AvatarDescriber,
+};
 
 // Constants for API endpoints and scopes
 const VISION_API_URL: &'static str = "https://vision.googleapis.com/v1/images:annotate";
 const CLOULD_PLATFORM_URL: &'static str = "https://www.googleapis.com/auth/cloud-platform";
 const CLOULD_TOKEN_URL: &'static str = "https://oauth2.googleapis.com/token";
 
+// Lifetime requested for access tokens
+const TOKEN_LIFETIME_SECS: u64 = 3600;
+// Refresh cached access tokens this long before they expire
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
 // JWT claims structure for Google authentication
 #[derive(Debug, Serialize)]
 struct Claims {
@@ -35,8 +47,20 @@ pub struct Response {
 // Structure for label annotations response
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LabelAnnotationsResponse {
-    #[serde(rename = "labelAnnotations")]
+    #[serde(rename = "labelAnnotations", default)]
     pub label_annotations: Vec<LabelAnnotation>,
+    // Error for this image, set instead of annotations when it failed
+    pub error: Option<ImageError>,
+}
+
+// Structure for a per-image error status
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageError {
+    // Google RPC status code
+    pub code: i64,
+    // Error message
+    #[serde(default)]
+    pub message: String,
 }
 
 // Structure for individual label annotation
@@ -61,6 +85,62 @@ pub struct GoogleVisionRequest {
     pub max_results: u8,
 }
 
+// Map Google token exchange errors, every refusal means the credentials are unusable
+fn classify_token(err: LeoError) -> LeoError {
+    match err {
+        LeoError::Api(api) if api.status < 500 => LeoError::Auth(api.to_string()),
+        err => err,
+    }
+}
+
+// Map Google Vision error payloads to the matching error class
+fn classify(err: LeoError) -> LeoError {
+    match err {
+        LeoError::Api(api) if is_exhausted(&api) => LeoError::Quota(api),
+        // Per-minute limits use the same code and pass within the backoff of the schedule
+        LeoError::Api(api) if api.status == 429 || api.has_code("RESOURCE_EXHAUSTED") => {
+            LeoError::RateLimited(api.to_string())
+        }
+        LeoError::Api(api) => LeoError::from_api_status(api),
+        err => err,
+    }
+}
+
+// Check whether the project ran out of its daily quota or has no billing, which no retry within
+// the day fixes. Google only tells these apart from short rate limits in the message
+fn is_exhausted(api: &ApiError) -> bool {
+    let message = api.message.to_lowercase();
+    message.contains("per day") || message.contains("billing")
+}
+
+// Map a per-image error of a successful response to the matching error class
+fn classify_image_error(error: &ImageError) -> LeoError {
+    // Google RPC codes, see google.rpc.Code
+    let (status, code) = match error.code {
+        3 => (400, "INVALID_ARGUMENT"),
+        7 => (403, "PERMISSION_DENIED"),
+        8 => (429, "RESOURCE_EXHAUSTED"),
+        16 => (401, "UNAUTHENTICATED"),
+        _ => (500, "UNKNOWN"),
+    };
+
+    classify(LeoError::Api(ApiError {
+        url: VISION_API_URL.to_string(),
+        status,
+        code: Some(code.to_string()),
+        message: error.message.clone(),
+    }))
+}
+
+// Access token cached across Vision requests
+#[derive(Debug, Clone)]
+struct CachedToken {
+    // Bearer token for the Vision API
+    access_token: String,
+    // Moment the token stops being accepted
+    expires_at: Instant,
+}
+
 // Main Google Vision API client
 #[derive(Debug)]
 pub struct GoogleVision {
@@ -68,86 +148,180 @@ pub struct GoogleVision {
     client_email: String,
     // Private key for authentication
     private_key: String,
-    // HTTP client instance
+    // Max results requested when describing avatars
+    max_results: u8,
+    // HTTP client for the token exchange
+    token_client: HttpClient,
+    // HTTP client for Vision requests
     http_client: HttpClient,
+    // Access token shared by all requests, refreshed before it expires
+    token: Mutex<Option<CachedToken>>,
 }
 
 impl GoogleVision {
     // Initialize a new Vision API client
-    pub fn initialize() -> Result<Self> {
+    pub fn initialize(config: &VisionConfig, http: &HttpConfig) -> Result<Self> {
         // Load service account credentials
-        let service_account_key: Value = serde_json::from_str(&std::fs::read_to_string("service_account.json")?)?;
-        let client_email = service_account_key["client_email"].as_str().unwrap();
-        let private_key = service_account_key["private_key"].as_str().unwrap();
+        let path = &config.service_account_file;
+        let service_account = std::fs::read_to_string(path)
+            .map_err(|err| LeoError::Config(format!("Unable to read {} {}", path, err)))?;
+        let service_account_key: Value = serde_json::from_str(&service_account)
+            .map_err(|err| LeoError::Config(format!("Invalid {} {}", path, err)))?;
+        let client_email = service_account_key["client_email"]
+            .as_str()
+            .ok_or_else(|| LeoError::Config(format!("Missing client_email in {}", path)))?;
+        let private_key = service_account_key["private_key"]
+            .as_str()
+            .ok_or_else(|| LeoError::Config(format!("Missing private_key in {}", path)))?;
 
         Ok(Self {
             client_email: client_email.into(),
             private_key: private_key.into(),
-            http_client: HttpClient::initialize(),
+            max_results: config.max_results,
+            token_client: HttpClient::initialize(&http.google_token)?,
+            http_client: HttpClient::initialize(&http.vision)?,
+            token: Mutex::new(None),
         })
     }
 
-    // Generate image descriptions using Vision API
-    pub fn generate_description(&self, request: GoogleVisionRequest) -> Result<Vec<String>> {
+    // Return a valid access token, exchanging a new JWT only when the cached one is about to expire
+    async fn access_token(&self) -> Result<String> {
+        // Holding the lock while refreshing makes concurrent callers wait for a single exchange
+        let mut cached = self.token.lock().await;
+        if let Some(token) = cached.as_ref() {
+            if token.expires_at > Instant::now() + TOKEN_REFRESH_MARGIN {
+                return Ok(token.access_token.clone());
+            }
+        }
+
+        let token = self.fetch_token().await?;
+        let access_token = token.access_token.clone();
+        *cached = Some(token);
+
+        Ok(access_token)
+    }
+
+    // Exchange a freshly signed JWT for an access token
+    async fn fetch_token(&self) -> Result<CachedToken> {
         // Get current timestamp
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as usize;
-        
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| LeoError::Auth(err.to_string()))?
+            .as_secs() as usize;
+
         // Create JWT claims
         let claims = Claims {
             iss: self.client_email.clone(),
             scope: CLOULD_PLATFORM_URL.to_string(),
             aud: CLOULD_TOKEN_URL.to_string(),
-            exp: now + 3600, // Token expires in 1 hour
+            exp: now + TOKEN_LIFETIME_SECS as usize,
             iat: now,
         };
 
         // Generate JWT token
         let header = Header::new(Algorithm::RS256);
-        let encoding_key = EncodingKey::from_rsa_pem(self.private_key.clone().as_bytes())?;
+        let encoding_key = EncodingKey::from_rsa_pem(self.private_key.as_bytes())?;
         let jwt = encode(&header, &claims, &encoding_key)?;
 
         // Get access token
-        let response: Value = self.http_client.send_post(
+        let requested_at = Instant::now();
+        let response: Value = self.token_client.send_post(
             CLOULD_TOKEN_URL,
             json!({
                 "grant_type": "urn:ietf:params:oauth:grant-type:jwt-bearer",
                 "assertion": jwt
             }),
-        )?;
+        ).await.map_err(classify_token)?;
 
-        let access_token = response["access_token"].to_string();
+        let access_token = response["access_token"]
+            .as_str()
+            .ok_or_else(|| LeoError::Auth("Token response contains no access_token".into()))?;
+        let expires_in = response["expires_in"].as_u64().unwrap_or(TOKEN_LIFETIME_SECS);
 
-        // Make Vision API request
-        let response = self.http_client.send_post_with_auth(
-            VISION_API_URL,
-            &access_token,
-            json!({
-              "requests": [
+        Ok(CachedToken {
+            access_token: access_token.to_string(),
+            expires_at: requested_at + Duration::from_secs(expires_in),
+        })
+    }
+
+    // Drop the cached token so the next request exchanges a new one, unless another request
+    // already replaced the rejected token
+    async fn invalidate_token(&self, rejected: &str) {
+        let mut cached = self.token.lock().await;
+        if cached.as_ref().is_some_and(|token| token.access_token == rejected) {
+            *cached = None;
+        }
+    }
+
+    // Send an annotate request with the given token
+    async fn annotate(&self, access_token: &str, body: &Value) -> Result<String> {
+        self.http_client
+            .send_post_with_auth(VISION_API_URL, access_token, body.clone())
+            .await
+            .map_err(classify)
+    }
+
+    // Generate image descriptions using Vision API
+    pub async fn generate_description(&self, request: GoogleVisionRequest) -> Result<Vec<String>> {
+        let body = json!({
+          "requests": [
+            {
+              "image": {
+                "content": request.image.to_base64()
+              },
+              "features": [
                 {
-                  "image": {
-                    "content": request.image.base64
-                  },
-                  "features": [
-                    {
-                      "type": "LABEL_DETECTION",
-                      "maxResults": request.max_results
-                    }
-                  ]
+                  "type": "LABEL_DETECTION",
+                  "maxResults": request.max_results
                 }
               ]
-            }),
-        )?;
+            }
+          ]
+        });
+
+        // Make Vision API request with the cached or refreshed access token
+        let access_token = self.access_token().await?;
+        let response = match self.annotate(&access_token, &body).await {
+            // A rejected token may have been revoked, exchange a new one and try once more
+            Err(LeoError::Auth(reason)) => {
+                println!("Vision access token rejected ({}), requesting a new one", reason);
+                self.invalidate_token(&access_token).await;
+                let access_token = self.access_token().await?;
+                self.annotate(&access_token, &body).await?
+            }
+            response => response?,
+        };
 
         // Parse and sort response
         let response: Response = serde_json::from_str(&response)?;
-        let mut descriptions: Vec<(&str, f64)> = response.responses[0]
+        let annotations = response
+            .responses
+            .first()
+            .ok_or_else(|| LeoError::Decode("Vision response contains no results".into()))?;
+        if let Some(error) = &annotations.error {
+            return Err(classify_image_error(error));
+        }
+        let mut descriptions: Vec<(&str, f64)> = annotations
             .label_annotations
             .iter()
             .map(|annotation| (&annotation.description[..], annotation.score))
             .collect();
-        descriptions.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        descriptions.sort_by(|a, b| b.1.total_cmp(&a.1));
         let sorted_descriptions: Vec<String> = descriptions.into_iter().map(|(desc, _)| desc.to_string()).collect();
 
         Ok(sorted_descriptions)
     }
+}
+
+// Implementation of AvatarDescriber trait for label detection
+#[async_trait]
+impl AvatarDescriber for GoogleVision {
+    // Describe avatar with labels sorted by confidence
+    async fn describe(&self, image: Image) -> Result<Vec<String>> {
+        self.generate_description(GoogleVisionRequest {
+            image,
+            max_results: self.max_results,
+        })
+        .await
+    }
 }
\ No newline at end of file